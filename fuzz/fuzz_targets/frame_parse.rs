@@ -0,0 +1,37 @@
+// Frame parsing must fail cleanly on any input, never panic, and whatever
+// it accepts must come out the same after a round trip
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The frame module depends on std alone, so it is built in here as is
+#[allow(dead_code)]
+#[path = "../../src/frame.rs"]
+mod frame;
+
+use frame::{Frame, FrameHeader};
+
+// The first 9 bytes are the frame header, the rest its payload
+fn parse(bytes: &[u8]) -> Option<Result<Frame, frame::Http2Error>> {
+    let header = FrameHeader::from_bytes(bytes.get(..9)?.try_into().ok()?);
+    Some(Frame::parse(header, &bytes[9..]))
+}
+
+fuzz_target!(|data: &[u8]| {
+    // Mostly fix up the length field, so that the payload parsers see
+    // every input rather than stop at a mismatched length
+    let mut data = data.to_vec();
+    if data.len() >= 9 && data[0] & 1 == 0 {
+        let length = (data.len() - 9) as u32;
+        data[..3].copy_from_slice(&length.to_be_bytes()[1..]);
+    }
+
+    let frame = match parse(&data) {
+        Some(Ok(frame)) => frame,
+        _ => return,
+    };
+
+    let reparsed = parse(&frame.serialize()).and_then(Result::ok);
+    assert_eq!(reparsed, Some(frame));
+});
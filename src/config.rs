@@ -0,0 +1,195 @@
+// Server configuration, shared by every connection and taken from the
+// command line
+
+#[cfg(feature = "static-files")]
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Streams a client may have open at once, unless configured otherwise
+pub const DEFAULT_MAX_CONCURRENT_STREAMS: u32 = 100;
+
+// Largest request body we accept unless configured otherwise
+pub const DEFAULT_MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+// How long an idle connection goes without frames before we PING it,
+// unless configured otherwise
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+// Backstop against abuse the specific checks miss: frames a connection
+// may send in total, and per second averaged over the rate window
+pub const DEFAULT_MAX_FRAMES: u64 = 10_000_000;
+pub const DEFAULT_MAX_FRAME_RATE: u64 = 10_000;
+
+// How long connections get to finish their open streams after a shutdown
+// request, unless configured otherwise
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Time a connection may go without any streams before it is closed,
+// unless configured otherwise
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Response bytes a connection produces and writes at a time, before the
+// event loop turns to other connections
+pub const DEFAULT_WRITE_BUDGET: usize = 256 * 1024;
+
+// Specification to follow where RFC 9113 changed the behavior of RFC 7540
+#[derive(Clone, Copy, PartialEq)]
+pub enum SpecLevel {
+    Rfc7540,
+    Rfc9113,
+}
+
+impl SpecLevel {
+    // RFC 9113 deprecates the RFC 7540 priority scheme; we then ignore
+    // priority signals and advertise SETTINGS_NO_RFC7540_PRIORITIES
+    pub fn honors_rfc7540_priorities(self) -> bool {
+        self == SpecLevel::Rfc7540
+    }
+
+    // RFC 9113 makes invalid characters in field names and values, and
+    // whitespace around values, malformed
+    pub fn strict_field_validation(self) -> bool {
+        self == SpecLevel::Rfc9113
+    }
+}
+
+// How connections are driven
+#[derive(Clone, Copy, PartialEq)]
+pub enum IoModel {
+    // A thread per connection, on blocking sockets
+    Threads,
+    // A single thread polling non-blocking sockets
+    EventLoop,
+}
+
+// What a malformed request gets
+#[derive(Clone, Copy, PartialEq)]
+pub enum MalformedPolicy {
+    // A 400 response, then a reset if the client is still sending
+    Respond,
+    // Only a reset of its stream
+    Reset,
+}
+
+// Configuration shared by every connection
+#[derive(Clone)]
+pub struct ServerConfig {
+    pub spec_level: SpecLevel,
+    pub max_body_size: u64,
+    pub keepalive_interval: Duration,
+    pub drain_timeout: Duration,
+    pub root: Option<PathBuf>,
+    pub max_frames: u64,
+    pub max_frame_rate: u64,
+    pub max_concurrent_streams: u32,
+    pub io_model: IoModel,
+    pub idle_timeout: Duration,
+    pub malformed_policy: MalformedPolicy,
+    pub server_timing: bool,
+    pub write_budget: usize,
+}
+
+impl ServerConfig {
+    // Build the configuration from the command line:
+    //   --spec 7540|9113        specification level (default 9113)
+    //   --max-body-size <bytes> largest accepted request body (default 1 MiB)
+    //   --keepalive <seconds>   idle time before we PING the client (default 30)
+    //   --drain-timeout <secs>  time open streams get on shutdown (default 30)
+    //   --root <dir>            serve static files from this directory
+    //   --max-frames <count>    frames allowed per connection (default 10 million)
+    //   --max-frame-rate <n>    frames per second allowed per connection (default 10000)
+    //   --max-concurrent-streams <n>  streams a client may open at once (default 100)
+    //   --io threads|event-loop how connections are driven (default event-loop)
+    //   --idle-timeout <secs>   time without streams before closing (default 300)
+    //   --malformed respond|reset  what malformed requests get (default respond)
+    //   --server-timing         report handler time in a server-timing header,
+    //                           never a trailer
+    //   --write-budget <bytes>  response bytes a connection writes before
+    //                           others get a turn (default 256 KiB)
+    pub fn from_args() -> Self {
+        let mut config = ServerConfig {
+            spec_level: SpecLevel::Rfc9113,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            root: None,
+            max_frames: DEFAULT_MAX_FRAMES,
+            max_frame_rate: DEFAULT_MAX_FRAME_RATE,
+            max_concurrent_streams: DEFAULT_MAX_CONCURRENT_STREAMS,
+            io_model: IoModel::EventLoop,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            malformed_policy: MalformedPolicy::Respond,
+            server_timing: false,
+            write_budget: DEFAULT_WRITE_BUDGET,
+        };
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--spec" => match args.next().as_deref() {
+                    Some("7540") => config.spec_level = SpecLevel::Rfc7540,
+                    Some("9113") => config.spec_level = SpecLevel::Rfc9113,
+                    other => eprintln!("Unknown spec level {:?}, using RFC 9113", other),
+                },
+                "--max-body-size" => match args.next().and_then(|value| value.parse().ok()) {
+                    Some(size) => config.max_body_size = size,
+                    None => eprintln!("Invalid --max-body-size, using {}", config.max_body_size),
+                },
+                "--keepalive" => match args.next().and_then(|value| value.parse().ok()) {
+                    Some(seconds) if seconds > 0 => config.keepalive_interval = Duration::from_secs(seconds),
+                    _ => eprintln!("Invalid --keepalive, using {:?}", config.keepalive_interval),
+                },
+                "--drain-timeout" => match args.next().and_then(|value| value.parse().ok()) {
+                    Some(seconds) => config.drain_timeout = Duration::from_secs(seconds),
+                    None => eprintln!("Invalid --drain-timeout, using {:?}", config.drain_timeout),
+                },
+                #[cfg(feature = "static-files")]
+                "--root" => match args.next().map(|dir| fs::canonicalize(&dir).map_err(|e| (dir, e))) {
+                    Some(Ok(root)) => config.root = Some(root),
+                    Some(Err((dir, e))) => eprintln!("Invalid --root {}: {}, not serving files", dir, e),
+                    None => eprintln!("Missing directory after --root"),
+                },
+                #[cfg(not(feature = "static-files"))]
+                "--root" => {
+                    args.next();
+                    eprintln!("Built without the static-files feature, not serving files");
+                }
+                "--max-frames" => match args.next().and_then(|value| value.parse().ok()) {
+                    Some(count) if count > 0 => config.max_frames = count,
+                    _ => eprintln!("Invalid --max-frames, using {}", config.max_frames),
+                },
+                "--max-frame-rate" => match args.next().and_then(|value| value.parse().ok()) {
+                    Some(rate) if rate > 0 => config.max_frame_rate = rate,
+                    _ => eprintln!("Invalid --max-frame-rate, using {}", config.max_frame_rate),
+                },
+                "--max-concurrent-streams" => match args.next().and_then(|value| value.parse().ok()) {
+                    Some(count) => config.max_concurrent_streams = count,
+                    None => eprintln!("Invalid --max-concurrent-streams, using {}", config.max_concurrent_streams),
+                },
+                "--io" => match args.next().as_deref() {
+                    Some("threads") => config.io_model = IoModel::Threads,
+                    Some("event-loop") => config.io_model = IoModel::EventLoop,
+                    other => eprintln!("Unknown I/O model {:?}, using the event loop", other),
+                },
+                "--idle-timeout" => match args.next().and_then(|value| value.parse().ok()) {
+                    Some(seconds) if seconds > 0 => config.idle_timeout = Duration::from_secs(seconds),
+                    _ => eprintln!("Invalid --idle-timeout, using {:?}", config.idle_timeout),
+                },
+                "--malformed" => match args.next().as_deref() {
+                    Some("respond") => config.malformed_policy = MalformedPolicy::Respond,
+                    Some("reset") => config.malformed_policy = MalformedPolicy::Reset,
+                    other => eprintln!("Unknown malformed request policy {:?}, responding", other),
+                },
+                "--server-timing" => config.server_timing = true,
+                "--write-budget" => match args.next().and_then(|value| value.parse().ok()) {
+                    Some(bytes) if bytes > 0 => config.write_budget = bytes,
+                    _ => eprintln!("Invalid --write-budget, using {}", config.write_budget),
+                },
+                _ => eprintln!("Ignoring unknown argument: {}", arg),
+            }
+        }
+
+        config
+    }
+}
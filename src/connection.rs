@@ -0,0 +1,897 @@
+// State of one client connection, kept between reads. A connection is fed
+// the bytes the client sends and produces the bytes to send back; it does
+// no I/O of its own, so it runs the same in a thread of its own or in the
+// event loop
+
+mod headers;
+mod http1;
+mod send;
+mod settings;
+mod stream;
+#[cfg(test)]
+mod tests;
+
+use std::collections::BTreeSet;
+use std::io::{self, ErrorKind, Write};
+use std::mem;
+use std::time::{Duration, Instant};
+use hpack::Decoder;
+
+use crate::config::ServerConfig;
+use crate::frame::{
+    self, Frame, FrameHeader, Http2Error, ACK_FLAG, CONTINUATION_FRAME_TYPE, ENHANCE_YOUR_CALM, NO_ERROR,
+    PROTOCOL_ERROR, SETTINGS_FRAME_TYPE,
+};
+use crate::message::{Request, Response};
+use crate::routes::handle_request;
+use crate::shutdown::shutdown_requested;
+use crate::warnings::{self, warning, WarningLog};
+
+use headers::{decode_header_block, HeaderSizes, MAX_HEADER_BLOCK_SIZE};
+use http1::{handle_http1_head, handle_http1_request, send_http1_response, Http1Start, MAX_HTTP1_HEAD_SIZE};
+use send::{send_goaway, send_ping, send_response, send_window_update, SendFlow};
+use settings::{
+    apply_client_settings, send_http2_settings_frame, update_settings, validate_frame_header, ServerSettings,
+    DEFAULT_WINDOW_SIZE,
+};
+use stream::{
+    handle_data_frame, handle_header_block, handle_window_update, may_open_stream, reset_stream, HeaderBlock, Streams,
+    MAX_STREAM_ID,
+};
+
+const CONNECTION_PREFACE: &[u8; 24] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Size of the connection receive window we open up right after the preface
+const CONNECTION_WINDOW_SIZE: u32 = 1024 * 1024;
+
+// How long we wait for the ACK of our keep-alive PING
+const PING_ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Opaque data of our keep-alive PING, echoed back in the ACK
+const KEEPALIVE_PING_DATA: [u8; 8] = *b"keepaliv";
+
+// Window over which the frame rate of a connection is averaged
+const FRAME_RATE_WINDOW: Duration = Duration::from_secs(10);
+
+// What the connection waits for next
+enum Phase {
+    // The HTTP/2 connection preface, or the head of an HTTP/1.1 request
+    Start,
+    // The body of an HTTP/1.1 request
+    Http1Body { request: Request, length: usize },
+    // The preface that follows a 101 response, with the client's settings
+    // from the upgrade request
+    UpgradePreface { request: Request, settings: Vec<u8> },
+    // The client's first SETTINGS frame, and the upgraded request to answer
+    // once it arrived
+    FirstSettings { upgrade: Option<Request> },
+    // Any HTTP/2 frame
+    Frames,
+    // Nothing; the connection is over once its output is written
+    Closed,
+}
+
+// How the client talks to us, decided once per connection
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Protocol {
+    // HTTP/2 from the first byte
+    PriorKnowledge,
+    // HTTP/2 after an HTTP/1.1 Upgrade: h2c
+    Upgrade,
+    // HTTP/1.1 throughout
+    Http1,
+}
+
+impl Protocol {
+    fn name(self) -> &'static str {
+        match self {
+            Protocol::PriorKnowledge => "h2-prior-knowledge",
+            Protocol::Upgrade => "h2c-upgrade",
+            Protocol::Http1 => "http/1.1",
+        }
+    }
+}
+
+pub struct Connection {
+    config: ServerConfig,
+    phase: Phase,
+    // Unknown until the preface or a complete HTTP/1.1 request arrived
+    protocol: Option<Protocol>,
+
+    // Bytes received but not processed yet, starting at `consumed`
+    input: Vec<u8>,
+    consumed: usize,
+    // Bytes to send to the client
+    output: Vec<u8>,
+    // Whether the socket should linger after `output`, see `linger`
+    linger: bool,
+
+    // Client's settings, and our own: the acknowledged ones, and the ones
+    // sent but not acknowledged yet. Our settings bind the client only
+    // once it acknowledges them; until then its frames are judged against
+    // the ones it knew before
+    settings: ServerSettings,
+    local_settings: ServerSettings,
+    pending_settings: Option<ServerSettings>,
+
+    // Send windows and response bodies waiting for them
+    flow: SendFlow,
+
+    // Per-connection HPACK decoding context, shared by all streams, and
+    // the sizes of the blocks it decoded
+    decoder: Decoder<'static>,
+    received_headers: HeaderSizes,
+
+    // Streams the client may still send frames on
+    streams: Streams,
+
+    // Header block waiting for its CONTINUATION frames
+    header_block: Option<HeaderBlock>,
+
+    // Header of the frame whose payload is still arriving. It was checked
+    // as soon as it was complete
+    pending_header: Option<FrameHeader>,
+
+    // When our keep-alive PING went out, if its ACK is still pending
+    ping_sent: Option<Instant>,
+
+    // Highest stream id the client has opened, reported in GOAWAY
+    last_stream_id: u32,
+
+    // Highest stream id the client has used. Unlike `last_stream_id`, the
+    // last stream we process, it includes streams refused during shutdown
+    highest_stream_id: u32,
+
+    // When the streams still open at shutdown must be done
+    drain_deadline: Option<Instant>,
+
+    frames: FrameCounter,
+
+    // When data last arrived, and when a stream was last busy
+    last_read: Instant,
+    last_stream_activity: Instant,
+
+    // Warnings emitted on this connection, counted to limit repeats
+    warnings: WarningLog,
+
+    // Bytes exchanged with the client, and the request body bytes among
+    // them; the response body bytes are counted by `flow`
+    bytes_received: u64,
+    bytes_sent: u64,
+    body_received: u64,
+}
+
+impl Connection {
+    pub fn new(config: ServerConfig) -> Self {
+        let settings = ServerSettings::new();
+        let flow = SendFlow::new(&settings, config.write_budget);
+        Connection {
+            config,
+            phase: Phase::Start,
+            protocol: None,
+            input: Vec::new(),
+            consumed: 0,
+            output: Vec::new(),
+            linger: false,
+            flow,
+            settings,
+            local_settings: ServerSettings::new(),
+            pending_settings: None,
+            decoder: Decoder::new(),
+            received_headers: HeaderSizes::default(),
+            streams: Streams::new(),
+            header_block: None,
+            pending_header: None,
+            ping_sent: None,
+            last_stream_id: 0,
+            highest_stream_id: 0,
+            drain_deadline: None,
+            frames: FrameCounter::new(),
+            last_read: Instant::now(),
+            last_stream_activity: Instant::now(),
+            warnings: WarningLog::default(),
+            bytes_received: 0,
+            bytes_sent: 0,
+            body_received: 0,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        matches!(self.phase, Phase::Closed)
+    }
+
+    // Whether the socket should linger once the output is written, so that
+    // the client gets to read our GOAWAY
+    pub fn lingers(&self) -> bool {
+        self.linger
+    }
+
+    // Everything produced since the last call, to be written to the client.
+    // Response bodies paused by the write budget then produce their next
+    // slice, for the next call
+    pub fn take_output(&mut self) -> Vec<u8> {
+        let output = mem::take(&mut self.output);
+        self.bytes_sent += output.len() as u64;
+
+        if matches!(self.phase, Phase::Frames) {
+            self.flow.burst = 0;
+            if let Err(e) = self.flow.send_pending(&mut self.output) {
+                self.with_warnings(|connection| connection.finalize(Err(e)));
+            }
+        }
+        output
+    }
+
+    // Bytes produced but not taken yet
+    pub fn pending_output(&self) -> usize {
+        self.output.len()
+    }
+
+    // Process data the client sent. Frames may arrive in any number of
+    // pieces; whatever is incomplete waits for the next call
+    pub fn receive(&mut self, data: &[u8]) {
+        self.with_warnings(|connection| connection.process(data));
+    }
+
+    fn process(&mut self, data: &[u8]) {
+        if self.is_closed() {
+            return;
+        }
+
+        self.input.extend_from_slice(data);
+        self.bytes_received += data.len() as u64;
+        self.last_read = Instant::now();
+
+        while !self.is_closed() {
+            match self.step() {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => self.finalize(Err(e)),
+            }
+        }
+
+        self.input.drain(..self.consumed);
+        self.consumed = 0;
+    }
+
+    // The client closed its side of the connection
+    pub fn receive_eof(&mut self) {
+        self.fail(io::Error::new(ErrorKind::UnexpectedEof, "connection closed by the client"));
+    }
+
+    // Reading from or writing to the client failed
+    pub fn fail(&mut self, error: io::Error) {
+        self.with_warnings(|connection| connection.finalize(Err(error.into())));
+    }
+
+    // Handle whatever depends on time rather than on client data. Called
+    // regularly, at least every `SHUTDOWN_POLL_INTERVAL`
+    pub fn on_tick(&mut self) {
+        self.with_warnings(|connection| {
+            if let Err(e) = connection.tick() {
+                connection.finalize(Err(e));
+            }
+        });
+    }
+
+    // Run `f` with the warnings it emits counted against this connection
+    fn with_warnings(&mut self, f: impl FnOnce(&mut Self)) {
+        warnings::enter(mem::take(&mut self.warnings));
+        f(self);
+        self.warnings = warnings::leave();
+    }
+
+    // Tear the connection down, exactly once; later calls do nothing. In
+    // order: a connection error is reported to the client in a GOAWAY
+    // frame (nothing more can be sent on a broken connection), streams
+    // still in progress are logged and dropped, the statistics and
+    // suppressed warnings are logged, and the summary line comes last
+    fn finalize(&mut self, result: Result<(), Http2Error>) {
+        if self.is_closed() {
+            return;
+        }
+        self.phase = Phase::Closed;
+
+        if let Err(e) = &result {
+            if !matches!(e, Http2Error::Io(_)) {
+                let _ = send_goaway(&mut self.output, self.last_stream_id, e.error_code(), e.debug_data());
+                self.linger = true;
+            }
+        }
+
+        let unfinished: BTreeSet<u32> = self.streams.receiving().chain(self.flow.streams.keys().copied()).collect();
+        for stream_id in unfinished {
+            println!("Stream {} ended with the connection", stream_id);
+        }
+        self.streams.clear();
+        self.flow.streams.clear();
+        self.header_block = None;
+
+        if self.received_headers.blocks > 0 || self.flow.sent_headers.blocks > 0 {
+            println!("Received {}", self.received_headers.summary());
+            println!("Sent {}", self.flow.sent_headers.summary());
+
+            let sent = self.bytes_sent + self.output.len() as u64;
+            let received = traffic_summary(self.bytes_received, self.body_received, self.received_headers.compressed);
+            println!("Received {}", received);
+            println!("Sent {}", traffic_summary(sent, self.flow.body_sent, self.flow.sent_headers.compressed));
+        }
+        warnings::log_suppressed();
+
+        let protocol = self.protocol.map(|protocol| format!(" ({})", protocol.name())).unwrap_or_default();
+        match result {
+            Ok(()) => println!("Connection closed{}", protocol),
+            Err(Http2Error::Io(e)) => eprintln!("Connection failed{}: {}", protocol, e),
+            Err(e) => eprintln!("Closing connection{}: {}", protocol, e),
+        }
+    }
+
+    fn set_protocol(&mut self, protocol: Protocol) {
+        debug_assert!(self.protocol.is_none(), "protocol already decided");
+        println!("Protocol: {}", protocol.name());
+        self.protocol = Some(protocol);
+    }
+
+    // The next `length` unprocessed bytes, once they have all arrived
+    fn take(&mut self, length: usize) -> Option<&[u8]> {
+        let start = self.consumed;
+        if self.input.len() - start < length {
+            return None;
+        }
+        self.consumed += length;
+        Some(&self.input[start..start + length])
+    }
+
+    // The next frame, once it has fully arrived. `check` runs on its header
+    // as soon as that is complete, before waiting for the payload
+    fn next_frame(
+        &mut self,
+        check: fn(&mut Self, &FrameHeader) -> Result<(), Http2Error>,
+    ) -> Result<Option<(FrameHeader, Frame)>, Http2Error> {
+        let header = match self.pending_header {
+            Some(header) => header,
+            None => {
+                let mut bytes = [0; 9];
+                match self.take(bytes.len()) {
+                    Some(received) => bytes.copy_from_slice(received),
+                    None => return Ok(None),
+                }
+                let header = FrameHeader::from_bytes(&bytes);
+                check(self, &header)?;
+                self.pending_header = Some(header);
+                header
+            }
+        };
+
+        let frame = match self.take(header.length as usize) {
+            Some(payload) => parse_frame(header, payload)?,
+            None => return Ok(None),
+        };
+        self.pending_header = None;
+        Ok(Some((header, frame)))
+    }
+
+    // Process the next complete unit of input. Returns false when more
+    // input is needed first
+    fn step(&mut self) -> Result<bool, Http2Error> {
+        match self.phase {
+            Phase::Start => self.start(),
+            Phase::Http1Body { length, .. } => self.read_http1_body(length),
+            Phase::UpgradePreface { .. } => self.read_upgrade_preface(),
+            Phase::FirstSettings { .. } => self.read_first_settings(),
+            Phase::Frames => self.read_frame(),
+            Phase::Closed => Ok(false),
+        }
+    }
+
+    // Tell an HTTP/2 connection preface from an HTTP/1.1 request, looking
+    // only as far as needed: a short HTTP/1.1 request may be all the client
+    // sends before waiting for the response
+    fn start(&mut self) -> Result<bool, Http2Error> {
+        let received = &self.input[self.consumed..];
+        let length = received.len().min(CONNECTION_PREFACE.len());
+        if received[..length] == CONNECTION_PREFACE[..length] {
+            if self.take(CONNECTION_PREFACE.len()).is_none() {
+                return Ok(false);
+            }
+
+            println!("Valid HTTP/2 connection preface received");
+            self.set_protocol(Protocol::PriorKnowledge);
+            self.start_http2()?;
+            self.phase = Phase::FirstSettings { upgrade: None };
+            return Ok(true);
+        }
+
+        // Anything else is the head of an HTTP/1.1 request
+        let limit = received.len().min(MAX_HTTP1_HEAD_SIZE);
+        let head = match received[..limit].windows(4).position(|bytes| bytes == b"\r\n\r\n") {
+            Some(position) => received[..position + 4].to_vec(),
+            None if received.len() >= MAX_HTTP1_HEAD_SIZE => {
+                self.set_protocol(Protocol::Http1);
+                send_http1_response(&mut self.output, Response::text(431, "Request Header Fields Too Large"))?;
+                self.finalize(Ok(()));
+                return Ok(true);
+            }
+            None => return Ok(false),
+        };
+        self.consumed += head.len();
+
+        match handle_http1_head(&self.config, &mut self.output, &head)? {
+            Http1Start::Body { request, length } => self.phase = Phase::Http1Body { request, length },
+            _ => {
+                self.set_protocol(Protocol::Http1);
+                self.finalize(Ok(()));
+            }
+        }
+        Ok(true)
+    }
+
+    // Wait for the body of an HTTP/1.1 request: after a 101 only HTTP/2
+    // frames follow
+    fn read_http1_body(&mut self, length: usize) -> Result<bool, Http2Error> {
+        let body = match self.take(length) {
+            Some(body) => body.to_vec(),
+            None => return Ok(false),
+        };
+        let mut request = match mem::replace(&mut self.phase, Phase::Closed) {
+            Phase::Http1Body { request, .. } => request,
+            _ => return Ok(false),
+        };
+        request.body = body;
+
+        match handle_http1_request(&self.config, &mut self.output, request)? {
+            Http1Start::Upgrade { request, settings } => {
+                self.set_protocol(Protocol::Upgrade);
+                self.start_http2()?;
+                self.phase = Phase::UpgradePreface { request, settings };
+            }
+            _ => {
+                self.set_protocol(Protocol::Http1);
+                self.finalize(Ok(()));
+            }
+        }
+        Ok(true)
+    }
+
+    // Send the server's SETTINGS frame, together with a WINDOW_UPDATE
+    // growing the connection window beyond the default
+    fn start_http2(&mut self) -> Result<(), Http2Error> {
+        let mut advertised_settings = ServerSettings::new();
+        advertised_settings.max_concurrent_streams = self.config.max_concurrent_streams;
+        send_http2_settings_frame(&mut self.output, &self.config, &advertised_settings)?;
+        self.pending_settings = Some(advertised_settings);
+        send_window_update(&mut self.output, 0, CONNECTION_WINDOW_SIZE - DEFAULT_WINDOW_SIZE)?;
+        Ok(())
+    }
+
+    // After a 101 the client still sends the preface, and its settings
+    // from the upgrade request apply until its SETTINGS frame arrives
+    fn read_upgrade_preface(&mut self) -> Result<bool, Http2Error> {
+        match self.take(CONNECTION_PREFACE.len()) {
+            None => return Ok(false),
+            Some(preface) if preface != CONNECTION_PREFACE => {
+                warning!("Invalid HTTP/2 connection preface");
+                return Err(Http2Error::Protocol(PROTOCOL_ERROR));
+            }
+            Some(_) => println!("Valid HTTP/2 connection preface received"),
+        }
+
+        if let Phase::UpgradePreface { request, settings } = mem::replace(&mut self.phase, Phase::Closed) {
+            update_settings(&mut self.settings, &frame::parse_settings(&settings));
+            self.phase = Phase::FirstSettings { upgrade: Some(request) };
+        }
+        Ok(true)
+    }
+
+    // The preface must be followed by a SETTINGS frame that is not an ACK
+    fn check_first_settings(&mut self, header: &FrameHeader) -> Result<(), Http2Error> {
+        if header.type_ != SETTINGS_FRAME_TYPE || header.flags & ACK_FLAG != 0 {
+            warning!("Expected SETTINGS frame, got frame type {} with flags {}", header.type_, header.flags);
+            return Err(Http2Error::Protocol(PROTOCOL_ERROR));
+        }
+        validate_frame_header(header, &self.local_settings)
+    }
+
+    fn read_first_settings(&mut self) -> Result<bool, Http2Error> {
+        let frame = match self.next_frame(Self::check_first_settings)? {
+            Some((_, frame)) => frame,
+            None => return Ok(false),
+        };
+        if let Frame::Settings { settings: pairs, .. } = frame {
+            apply_client_settings(&mut self.output, &mut self.settings, &pairs)?;
+        }
+
+        self.flow = SendFlow::new(&self.settings, self.config.write_budget);
+        self.flow.apply_settings(&self.settings)?;
+
+        // The upgraded request is stream 1, already half-closed by the client
+        if let Phase::FirstSettings { upgrade: Some(request) } = mem::replace(&mut self.phase, Phase::Frames) {
+            self.last_stream_id = 1;
+            self.highest_stream_id = 1;
+            self.flow.open(1);
+            send_response(&mut self.output, &mut self.flow, 1, handle_request(&self.config, &request))?;
+        }
+        Ok(true)
+    }
+
+    // Whether any stream still needs the connection
+    fn busy(&self) -> bool {
+        self.streams.receiving().next().is_some() || !self.flow.streams.is_empty() || self.header_block.is_some()
+    }
+
+    // Once shutting down, close as soon as the open streams are done;
+    // streams that were reset or already answered need nothing more
+    fn close_if_drained(&mut self) -> bool {
+        if self.drain_deadline.is_some() && !self.busy() {
+            println!("All streams finished, closing connection");
+            self.finalize(Ok(()));
+            return true;
+        }
+        false
+    }
+
+    // Stop accepting new streams. The client is told which streams we will
+    // still finish; it has to retry any later ones elsewhere
+    fn start_draining(&mut self) -> Result<(), Http2Error> {
+        send_goaway(&mut self.output, self.last_stream_id, NO_ERROR, "")?;
+        self.drain_deadline = Some(Instant::now() + self.config.drain_timeout);
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<(), Http2Error> {
+        match self.phase {
+            Phase::Frames => {}
+            Phase::Closed => return Ok(()),
+            // Before the first frames there are no streams to drain
+            _ => {
+                if shutdown_requested() {
+                    self.finalize(Ok(()));
+                } else if self.last_read.elapsed() >= self.config.idle_timeout {
+                    println!("Connection idle for {:?}, closing", self.config.idle_timeout);
+                    self.finalize(Ok(()));
+                }
+                return Ok(());
+            }
+        }
+
+        // A connection that stays idle for the keep-alive interval gets a
+        // PING, and is given up on when the ACK does not come back in time
+        if self.ping_sent.is_some_and(|sent| sent.elapsed() >= PING_ACK_TIMEOUT) {
+            return Err(io::Error::new(ErrorKind::TimedOut, "PING acknowledgment timed out").into());
+        }
+        if self.ping_sent.is_none() && self.last_read.elapsed() >= self.config.keepalive_interval {
+            println!("Connection idle, sending PING");
+            send_ping(&mut self.output, &KEEPALIVE_PING_DATA, false)?;
+            self.ping_sent = Some(Instant::now());
+        }
+
+        match self.drain_deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                println!("Drain timeout expired, closing connection");
+                self.finalize(Ok(()));
+                return Ok(());
+            }
+            Some(_) => {}
+            None if shutdown_requested() => {
+                println!("Shutting down, draining open streams");
+                self.start_draining()?;
+            }
+            None => {}
+        }
+        if self.close_if_drained() {
+            return Ok(());
+        }
+
+        // Without streams for the idle timeout, the client has no more use
+        // for the connection; keep-alive PINGs do not count
+        if self.busy() {
+            self.last_stream_activity = Instant::now();
+        } else if self.drain_deadline.is_none() && self.last_stream_activity.elapsed() >= self.config.idle_timeout {
+            println!("Connection idle for {:?}, closing", self.config.idle_timeout);
+            send_goaway(&mut self.output, self.last_stream_id, NO_ERROR, "")?;
+            self.finalize(Ok(()));
+        }
+
+        Ok(())
+    }
+
+    fn check_frame_header(&mut self, header: &FrameHeader) -> Result<(), Http2Error> {
+        self.frames.record(&self.config)?;
+        validate_frame_header(header, &self.local_settings)?;
+
+        // A header block must be contiguous: only CONTINUATION frames of
+        // the same stream may follow until END_HEADERS
+        if let Some(block) = &self.header_block {
+            if header.type_ != CONTINUATION_FRAME_TYPE || header.stream_id != block.stream_id {
+                warning!("Frame type {} interrupted the header block of stream {}", header.type_, block.stream_id);
+                return Err(Http2Error::Protocol(PROTOCOL_ERROR));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_frame(&mut self) -> Result<bool, Http2Error> {
+        if self.close_if_drained() {
+            return Ok(false);
+        }
+
+        let (header, frame) = match self.next_frame(Self::check_frame_header)? {
+            Some(frame) => frame,
+            None => return Ok(false),
+        };
+        if header.stream_id != 0 {
+            self.last_stream_activity = Instant::now();
+        }
+
+        self.handle_frame(header, frame)?;
+        Ok(true)
+    }
+
+    // Decode a complete header block, from HEADERS alone or with its
+    // CONTINUATION frames, and act on it. Even a refused block updates the
+    // HPACK state shared by all streams
+    fn finish_header_block(&mut self, block: HeaderBlock) -> Result<(), Http2Error> {
+        let headers = decode_header_block(&mut self.decoder, &mut self.received_headers, &block)?;
+        let accept_new_stream = self.streams.contains_key(&block.stream_id)
+            || may_open_stream(&self.config, &self.streams, &self.flow, block.stream_id, self.last_stream_id);
+        let (writer, streams, flow) = (&mut self.output, &mut self.streams, &mut self.flow);
+        handle_header_block(&self.config, writer, streams, flow, block, headers, accept_new_stream)
+    }
+
+    fn handle_frame(&mut self, header: FrameHeader, frame: Frame) -> Result<(), Http2Error> {
+        let config = &self.config;
+        let writer = &mut self.output;
+        let streams = &mut self.streams;
+        let flow = &mut self.flow;
+
+        match frame {
+            Frame::WindowUpdate { stream_id, increment } => {
+                println!("Window size increment: {}", increment);
+                handle_window_update(writer, streams, flow, stream_id, increment, self.highest_stream_id)?;
+            }
+            Frame::Headers { stream_id, end_stream, end_headers, priority, fragment, .. } => {
+                // Only trailers may arrive on a known stream, or on one we
+                // just reset. A new stream needs an odd id above every one
+                // the client used before
+                let known = streams.contains_key(&stream_id) || streams.was_reset(stream_id);
+                if !known && (stream_id % 2 == 0 || stream_id <= self.highest_stream_id) {
+                    warning!("HEADERS frame cannot open stream {} after stream {}", stream_id, self.highest_stream_id);
+                    return Err(Http2Error::Protocol(PROTOCOL_ERROR));
+                }
+
+                let block = HeaderBlock {
+                    stream_id,
+                    end_stream,
+                    priority,
+                    fragments: fragment,
+                };
+                self.highest_stream_id = self.highest_stream_id.max(stream_id);
+                if self.drain_deadline.is_none() {
+                    self.last_stream_id = self.last_stream_id.max(stream_id);
+
+                    // Stream ids are 31 bits: once the client used the last
+                    // one, it has to open another connection for more
+                    if stream_id > MAX_STREAM_ID - 2 {
+                        println!("Stream ids exhausted, draining open streams");
+                        self.start_draining()?;
+                    }
+                }
+
+                // Without END_HEADERS, the block continues in CONTINUATION frames
+                if !end_headers {
+                    self.header_block = Some(block);
+                } else {
+                    self.finish_header_block(block)?;
+                }
+            }
+            Frame::Continuation { end_headers, fragment, .. } => {
+                let mut block = match self.header_block.take() {
+                    Some(block) => block,
+                    None => {
+                        warning!("CONTINUATION frame without a header block in progress");
+                        return Err(Http2Error::Protocol(PROTOCOL_ERROR));
+                    }
+                };
+
+                // An empty fragment is legal and does not end the block by itself
+                block.fragments.extend_from_slice(&fragment);
+
+                if block.fragments.len() > MAX_HEADER_BLOCK_SIZE {
+                    warning!("Header block exceeded {} bytes on stream {}", MAX_HEADER_BLOCK_SIZE, block.stream_id);
+                    return Err(Http2Error::Protocol(ENHANCE_YOUR_CALM));
+                }
+
+                if !end_headers {
+                    self.header_block = Some(block);
+                } else {
+                    self.finish_header_block(block)?;
+                }
+            }
+            Frame::Data { stream_id, data, .. } => {
+                // DATA on a stream that was never opened is a connection error
+                if stream_id > self.highest_stream_id {
+                    warning!("DATA frame on idle stream {}", stream_id);
+                    return Err(Http2Error::Protocol(PROTOCOL_ERROR));
+                }
+
+                // Every DATA byte, padding included, counts against the
+                // connection window; we consume it right away
+                if header.length > 0 {
+                    send_window_update(writer, 0, header.length)?;
+                }
+
+                self.body_received += data.len() as u64;
+                handle_data_frame(config, writer, streams, flow, &header, data)?;
+            }
+            Frame::Settings { ack: true, .. } => {
+                // Our settings are in effect from here on
+                println!("Received SETTINGS acknowledgment");
+                match self.pending_settings.take() {
+                    Some(settings) => self.local_settings = settings,
+                    None => warning!("SETTINGS acknowledgment without pending settings"),
+                }
+            }
+            Frame::Settings { ack: false, settings: pairs } => {
+                println!("Received additional SETTINGS frame");
+                apply_client_settings(writer, &mut self.settings, &pairs)?;
+                flow.apply_settings(&self.settings)?;
+                flow.send_pending(writer)?;
+            }
+            Frame::RstStream { stream_id, error_code } => {
+                println!("Error code: {}", error_code);
+
+                // Resetting a stream that was never opened is a connection error
+                if stream_id > self.highest_stream_id {
+                    warning!("RST_STREAM frame on idle stream {}", stream_id);
+                    return Err(Http2Error::Protocol(PROTOCOL_ERROR));
+                }
+
+                // The stream is closed in both directions: stop sending its
+                // response. The client sends nothing more on it
+                streams.remove(&stream_id);
+                flow.close(stream_id);
+            }
+            Frame::Ping { ack, data } => {
+                handle_ping(writer, ack, data, &mut self.ping_sent)?;
+            }
+            Frame::Goaway { last_stream_id, error_code, debug_data } => {
+                // Close the connection after receiving a GOAWAY frame
+                println!("Last stream ID: {}", last_stream_id);
+                println!("Error code: {}", error_code);
+                if !debug_data.is_empty() {
+                    println!("Debug data: {:?}", String::from_utf8_lossy(&debug_data));
+                }
+                println!("Closing connection due to GOAWAY frame");
+                self.finalize(Ok(()));
+            }
+            Frame::Priority { stream_id, priority } => {
+                // Priority may be sent for any stream, even an idle one,
+                // and never opens it
+                if priority.dependency == stream_id {
+                    // Only a stream in progress can be reset; an idle or
+                    // closed one has nothing to fail
+                    warning!("Stream {} depends on itself", stream_id);
+                    let receiving = streams.contains_key(&stream_id);
+                    if receiving || flow.streams.contains_key(&stream_id) {
+                        reset_stream(writer, streams, flow, stream_id, PROTOCOL_ERROR, !receiving)?;
+                    }
+                } else if config.spec_level.honors_rfc7540_priorities() {
+                    println!(
+                        "Priority: exclusive={}, dependency={}, weight={}",
+                        priority.exclusive, priority.dependency, priority.weight
+                    );
+                }
+            }
+            Frame::PushPromise { .. } => {
+                // Only servers push
+                return Err(Http2Error::Violation(PROTOCOL_ERROR, "PUSH_PROMISE sent by a client"));
+            }
+            Frame::Unknown { .. } => {
+                // Extensions may add frame types; ones we do not know are
+                // ignored. A header block in progress already rejected them
+                println!("Ignoring frame of unknown type {}", header.type_);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// A connection dropped while still open, as when a panic unwinds through
+// its thread, is torn down all the same
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if !self.is_closed() {
+            let error = io::Error::other("connection dropped while open");
+            self.with_warnings(|connection| connection.finalize(Err(error.into())));
+        }
+    }
+}
+
+// Split the bytes of one direction into content, body and header blocks,
+// and protocol overhead: frame headers, padding, control frames and, for
+// h2c, the HTTP/1.1 upgrade exchange
+fn traffic_summary(total: u64, body: u64, header_blocks: usize) -> String {
+    let content = body + header_blocks as u64;
+    let overhead = total.saturating_sub(content);
+    format!(
+        "{} bytes: {} of bodies, {} of header blocks, {} of overhead ({:.1}%)",
+        total,
+        body,
+        header_blocks,
+        overhead,
+        overhead as f64 * 100.0 / total.max(1) as f64
+    )
+}
+
+// Frames received on a connection, in total and in the current rate window
+struct FrameCounter {
+    total: u64,
+    window_start: Instant,
+    window_frames: u64,
+}
+
+impl FrameCounter {
+    fn new() -> Self {
+        FrameCounter {
+            total: 0,
+            window_start: Instant::now(),
+            window_frames: 0,
+        }
+    }
+
+    // Count one frame, failing once the connection exceeds either ceiling
+    fn record(&mut self, config: &ServerConfig) -> Result<(), Http2Error> {
+        self.total += 1;
+        if self.total > config.max_frames {
+            warning!("Connection exceeded {} frames", config.max_frames);
+            return Err(Http2Error::Protocol(ENHANCE_YOUR_CALM));
+        }
+
+        if self.window_start.elapsed() >= FRAME_RATE_WINDOW {
+            self.window_start = Instant::now();
+            self.window_frames = 0;
+        }
+        self.window_frames += 1;
+        if self.window_frames > config.max_frame_rate * FRAME_RATE_WINDOW.as_secs() {
+            warning!("Connection exceeded {} frames per second", config.max_frame_rate);
+            return Err(Http2Error::Protocol(ENHANCE_YOUR_CALM));
+        }
+
+        Ok(())
+    }
+}
+
+// Parse the payload of a frame whose header passed validation
+fn parse_frame(header: FrameHeader, payload: &[u8]) -> Result<Frame, Http2Error> {
+    println!(
+        "Received {} frame: length={}, flags={}, stream_id={}",
+        header.type_name(),
+        header.length,
+        header.flags,
+        header.stream_id
+    );
+
+    Frame::parse(header, payload)
+}
+
+// Answer a PING with an ACK echoing its data. `ping_sent` tracks our own
+// keep-alive PING and is cleared when its ACK arrives
+fn handle_ping(
+    writer: &mut impl Write,
+    ack: bool,
+    data: [u8; 8],
+    ping_sent: &mut Option<Instant>,
+) -> Result<(), Http2Error> {
+    // An ACK must not be acknowledged again
+    if ack {
+        println!("Received PING acknowledgment");
+        if data == KEEPALIVE_PING_DATA {
+            *ping_sent = None;
+        }
+        return Ok(());
+    }
+
+    send_ping(writer, &data, true)
+}
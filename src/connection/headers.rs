@@ -0,0 +1,238 @@
+// HPACK header blocks: decoding the client's, within limits, and
+// encoding ours
+
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use hpack::encoder::encode_integer;
+use hpack::Decoder;
+
+use crate::frame::Http2Error;
+use crate::message::{HeaderList, STATIC_TABLE};
+use crate::warnings::warning;
+
+use super::stream::HeaderBlock;
+
+// Largest header block we assemble from HEADERS and CONTINUATION frames
+pub const MAX_HEADER_BLOCK_SIZE: usize = 256 * 1024;
+
+// Header blocks at least this large need a decode slot
+const LARGE_HEADER_BLOCK_SIZE: usize = 16 * 1024;
+
+// How many large header blocks may be decoded at once, across all connections
+const MAX_LARGE_DECODES: usize = 4;
+
+// HPACK dynamic table size both sides start with; we never advertise
+// another one for our decoder
+pub const DEFAULT_HEADER_TABLE_SIZE: u32 = 4096;
+
+// Large header blocks being decoded right now, across all connections
+static LARGE_DECODES: Mutex<usize> = Mutex::new(0);
+static LARGE_DECODE_DONE: Condvar = Condvar::new();
+
+// Permission to decode a large header block, given back when dropped
+struct DecodeSlot;
+
+impl DecodeSlot {
+    // Wait for a free slot, returning it along with the time spent waiting
+    fn acquire() -> (Self, Duration) {
+        let start = Instant::now();
+        let mut active = LARGE_DECODES.lock().unwrap_or_else(|e| e.into_inner());
+        while *active >= MAX_LARGE_DECODES {
+            active = LARGE_DECODE_DONE.wait(active).unwrap_or_else(|e| e.into_inner());
+        }
+        *active += 1;
+        (DecodeSlot, start.elapsed())
+    }
+}
+
+impl Drop for DecodeSlot {
+    fn drop(&mut self) {
+        *LARGE_DECODES.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+        LARGE_DECODE_DONE.notify_one();
+    }
+}
+
+// Header blocks of one direction of a connection: their size on the wire,
+// and the header list size they decode to (RFC 9113 section 6.5.2)
+#[derive(Default)]
+pub struct HeaderSizes {
+    pub blocks: u64,
+    pub compressed: usize,
+    pub uncompressed: usize,
+}
+
+impl HeaderSizes {
+    // Count a block of `compressed` bytes and the header list size of its
+    // fields
+    pub fn add(&mut self, compressed: usize, fields: &HeaderList) {
+        self.blocks += 1;
+        self.compressed += compressed;
+        self.uncompressed += fields.iter().map(|(name, value)| name.len() + value.len() + 32).sum::<usize>();
+    }
+
+    pub fn summary(&self) -> String {
+        let ratio = self.compressed as f64 * 100.0 / self.uncompressed.max(1) as f64;
+        format!(
+            "{} header block(s), {} bytes for {} of fields ({:.0}%)",
+            self.blocks, self.compressed, self.uncompressed, ratio
+        )
+    }
+}
+
+// Decode a complete header block
+pub fn decode_header_block(
+    decoder: &mut Decoder,
+    sizes: &mut HeaderSizes,
+    block: &HeaderBlock,
+) -> Result<HeaderList, Http2Error> {
+    check_table_size_updates(&block.fragments)?;
+
+    // Decoding expands a block into owned fields; queue large blocks so
+    // that only a few of them are expanded in memory at the same time
+    let _slot = if block.fragments.len() >= LARGE_HEADER_BLOCK_SIZE {
+        let (slot, waited) = DecodeSlot::acquire();
+        if waited >= Duration::from_millis(1) {
+            println!("Waited {:?} to decode a {}-byte header block", waited, block.fragments.len());
+        }
+        Some(slot)
+    } else {
+        None
+    };
+
+    match decoder.decode(&block.fragments) {
+        Ok(headers) => {
+            sizes.add(block.fragments.len(), &headers);
+            println!("Decoded headers:");
+            for (name, value) in &headers {
+                println!("{}: {}", String::from_utf8_lossy(name), String::from_utf8_lossy(value));
+            }
+            Ok(headers)
+        }
+        Err(e) => {
+            warning!("Failed to decode headers: {:?}", e);
+            Err(Http2Error::Compression)
+        }
+    }
+}
+
+// Dynamic table size updates open a header block. The client may shrink
+// our decoder's table down to zero, which the decoder handles by evicting
+// entries, but may never grow it past the size we allow
+fn check_table_size_updates(fragments: &[u8]) -> Result<(), Http2Error> {
+    let mut rest = fragments;
+    while let Some(&first) = rest.first() {
+        if first & 0xe0 != 0x20 {
+            break;
+        }
+
+        let (size, consumed) = match decode_prefixed_integer(rest, 5) {
+            Some(decoded) => decoded,
+            None => {
+                warning!("Truncated dynamic table size update");
+                return Err(Http2Error::Compression);
+            }
+        };
+        if size > DEFAULT_HEADER_TABLE_SIZE as usize {
+            warning!("Dynamic table size update to {} exceeds {}", size, DEFAULT_HEADER_TABLE_SIZE);
+            return Err(Http2Error::Compression);
+        }
+
+        println!("Dynamic table size update to {}", size);
+        rest = &rest[consumed..];
+    }
+
+    Ok(())
+}
+
+// Decode an HPACK integer with the given prefix size, returning its value
+// and the number of bytes it took
+fn decode_prefixed_integer(bytes: &[u8], prefix_size: u8) -> Option<(usize, usize)> {
+    let mask = (1usize << prefix_size) - 1;
+    let mut value = (*bytes.first()? as usize) & mask;
+    if value < mask {
+        return Some((value, 1));
+    }
+
+    let mut shift = 0;
+    for (i, &byte) in bytes.iter().enumerate().skip(1) {
+        value = value.checked_add(((byte & 0x7f) as usize).checked_shl(shift)?)?;
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+        shift += 7;
+        if shift > 28 {
+            return None;
+        }
+    }
+
+    None
+}
+
+// HPACK-encode a header block that never touches the dynamic table
+pub fn encode_without_indexing(fields: &HeaderList) -> Vec<u8> {
+    // Shrink the client's view of our table to zero, so nothing can ever
+    // be referenced from it
+    let mut block = table_size_update(0);
+
+    for (name, value) in fields {
+        // Indexed field, when the static table has the exact entry
+        if let Some(index) = STATIC_TABLE.iter().position(|&(n, v)| n == &name[..] && v == &value[..]) {
+            let mut indexed = encode_integer(index + 1, 7);
+            indexed[0] |= 0x80;
+            block.extend(indexed);
+            continue;
+        }
+
+        // Literal field without indexing, with the name from the static
+        // table where possible
+        match STATIC_TABLE.iter().position(|&(n, _)| n == &name[..]) {
+            Some(index) => block.extend(encode_integer(index + 1, 4)),
+            None => {
+                block.push(0x00);
+                block.extend(encode_string_literal(name));
+            }
+        }
+        block.extend(encode_string_literal(value));
+    }
+
+    block
+}
+
+// HPACK dynamic table size update
+pub fn table_size_update(size: usize) -> Vec<u8> {
+    let mut update = encode_integer(size, 5);
+    update[0] |= 0x20;
+    update
+}
+
+// HPACK string literal, without Huffman coding
+fn encode_string_literal(bytes: &[u8]) -> Vec<u8> {
+    let mut literal = encode_integer(bytes.len(), 7);
+    literal.extend_from_slice(bytes);
+    literal
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn large_header_blocks_wait_for_a_decode_slot() {
+        let slots: Vec<_> = (0..MAX_LARGE_DECODES).map(|_| DecodeSlot::acquire().0).collect();
+        let waiting = thread::spawn(|| {
+            let _slot = DecodeSlot::acquire();
+            Instant::now()
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiting.is_finished());
+
+        // However late the thread started, it got its slot only after ours
+        // were given back
+        let released = Instant::now();
+        drop(slots);
+        assert!(waiting.join().unwrap() >= released);
+    }
+}
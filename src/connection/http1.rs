@@ -0,0 +1,206 @@
+// HTTP/1.1 requests, answered directly or upgraded to h2c
+
+use std::io::Write;
+
+use crate::config::ServerConfig;
+use crate::frame::Http2Error;
+use crate::message::{intern_header_name, HeaderMap, Request, Response};
+use crate::routes::handle_request;
+use crate::warnings::warning;
+
+// Largest HTTP/1.1 request head we read before the upgrade to HTTP/2
+pub const MAX_HTTP1_HEAD_SIZE: usize = 16 * 1024;
+
+// What an HTTP/1.1 request leads to
+pub enum Http1Start {
+    // Its body has yet to arrive
+    Body { request: Request, length: usize },
+    // An upgrade to h2c. The request becomes stream 1, and the
+    // HTTP2-Settings payload holds the client's initial settings
+    Upgrade { request: Request, settings: Vec<u8> },
+    // It was answered over HTTP/1.1, and the connection closes
+    Answered,
+}
+
+// Parse the head of an HTTP/1.1 request, answering it right away when it
+// cannot be served
+pub fn handle_http1_head(
+    config: &ServerConfig,
+    writer: &mut impl Write,
+    head: &[u8],
+) -> Result<Http1Start, Http2Error> {
+    let request = match parse_http1_head(head) {
+        Ok(request) => request,
+        Err(reason) => {
+            warning!("Malformed HTTP/1.1 request: {}", reason);
+            send_http1_response(writer, Response::text(400, "Bad Request"))?;
+            return Ok(Http1Start::Answered);
+        }
+    };
+    println!("Received HTTP/1.1 request: {} {}", request.method, request.path);
+
+    if request.headers.contains_key("transfer-encoding") {
+        send_http1_response(writer, Response::text(501, "Not Implemented"))?;
+        return Ok(Http1Start::Answered);
+    }
+    let length = match request.headers.get("content-length").map(|value| value.parse::<u64>()) {
+        None => 0,
+        Some(Ok(length)) if length <= config.max_body_size => length,
+        Some(Ok(_)) => {
+            send_http1_response(writer, Response::text(413, "Payload Too Large"))?;
+            return Ok(Http1Start::Answered);
+        }
+        Some(Err(_)) => {
+            send_http1_response(writer, Response::text(400, "Bad Request"))?;
+            return Ok(Http1Start::Answered);
+        }
+    };
+
+    Ok(Http1Start::Body { request, length: length as usize })
+}
+
+// Answer an HTTP/1.1 request whose body has arrived. An h2c upgrade
+// request is accepted with a 101 response; anything else is answered over
+// HTTP/1.1 and the connection closed
+pub fn handle_http1_request(
+    config: &ServerConfig,
+    writer: &mut impl Write,
+    mut request: Request,
+) -> Result<Http1Start, Http2Error> {
+    // RFC 7540 section 3.2: the upgrade needs exactly one HTTP2-Settings
+    // header, itself named in Connection
+    let has_token = |name: &str, token: &str| {
+        request.headers.get(name).is_some_and(|value| {
+            value.split(',').any(|item| item.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    let upgrade = has_token("upgrade", "h2c") && has_token("connection", "upgrade") && has_token("connection", "http2-settings");
+    let settings = match request.headers.get("http2-settings") {
+        Some(value) if upgrade && !value.contains(',') => decode_base64url(value.trim()),
+        _ => None,
+    };
+
+    for name in ["connection", "upgrade", "http2-settings", "keep-alive"] {
+        request.headers.remove(name);
+    }
+
+    match settings {
+        Some(settings) if settings.len().is_multiple_of(6) => {
+            println!("Upgrading connection to h2c");
+            writer.write_all(b"HTTP/1.1 101 Switching Protocols\r\nconnection: Upgrade\r\nupgrade: h2c\r\n\r\n")?;
+            Ok(Http1Start::Upgrade { request, settings })
+        }
+        _ => {
+            let response = handle_request(config, &request);
+            send_http1_response(writer, response)?;
+            Ok(Http1Start::Answered)
+        }
+    }
+}
+
+// Parse an HTTP/1.1 request line and header fields into a request like
+// the ones decoded from HEADERS frames
+fn parse_http1_head(head: &[u8]) -> Result<Request, &'static str> {
+    let text = std::str::from_utf8(head).map_err(|_| "request head is not UTF-8")?;
+    let mut lines = text.split("\r\n");
+
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (method, path) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version), None) if !method.is_empty() && version.starts_with("HTTP/1.") => {
+            (method, path)
+        }
+        _ => return Err("bad request line"),
+    };
+    if !path.starts_with('/') {
+        return Err("request target is not a path");
+    }
+
+    let mut authority = None;
+    let mut headers = HeaderMap::new();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':').ok_or("header line without a colon")?;
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err("bad header name");
+        }
+        let name = intern_header_name(name.as_bytes());
+        let value = value.trim().to_string();
+
+        // Host takes the place of :authority
+        if name == "host" {
+            authority = Some(value);
+            continue;
+        }
+
+        let separator = if name == "cookie" { "; " } else { ", " };
+        headers
+            .entry(name)
+            .and_modify(|existing| {
+                existing.push_str(separator);
+                existing.push_str(&value);
+            })
+            .or_insert(value);
+    }
+
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        scheme: Some("http".to_string()),
+        authority,
+        headers,
+        body: Vec::new(),
+    })
+}
+
+// Write a response over HTTP/1.1, closing the connection after it
+pub fn send_http1_response(writer: &mut impl Write, mut response: Response) -> Result<(), Http2Error> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        _ => "",
+    };
+
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, reason);
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("connection: close\r\n\r\n");
+
+    writer.write_all(head.as_bytes())?;
+    response.body.write_to(writer)?;
+    Ok(())
+}
+
+// Decode base64url without padding, as used by the HTTP2-Settings header
+fn decode_base64url(input: &str) -> Option<Vec<u8>> {
+    let input = input.trim_end_matches('=');
+    if input.len() % 4 == 1 {
+        return None;
+    }
+
+    let mut decoded = Vec::with_capacity(input.len() * 3 / 4);
+    let mut bits: u32 = 0;
+    let mut count = 0;
+    for c in input.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | u32::from(value);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+    Some(decoded)
+}
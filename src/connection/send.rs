@@ -0,0 +1,432 @@
+// Send side of the connection: response headers, and response bodies
+// paced by flow control
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use hpack::Encoder;
+
+use crate::frame::{
+    ErrorCode, Frame, FrameHeader, Http2Error, DATA_FRAME_TYPE, END_STREAM_FLAG, FLOW_CONTROL_ERROR, INTERNAL_ERROR,
+};
+use crate::message::{Body, HeaderList, Response};
+use crate::warnings::warning;
+
+use super::headers::{encode_without_indexing, table_size_update, HeaderSizes, DEFAULT_HEADER_TABLE_SIZE};
+use super::settings::{ServerSettings, DEFAULT_WINDOW_SIZE};
+
+// Largest flow-control window, a window may never grow beyond it
+pub const MAX_WINDOW_SIZE: i64 = 0x7fff_ffff;
+
+// Send side of a stream whose response is not complete yet
+pub struct SendStream {
+    // Flow-control window; a smaller SETTINGS_INITIAL_WINDOW_SIZE can
+    // make it negative
+    pub window: i64,
+    // Response body, once the response headers went out
+    body: Option<Body>,
+    // Bytes of the body already written
+    sent: u64,
+    // Reset the stream with this error code after the last DATA frame
+    reset_when_done: Option<ErrorCode>,
+    // Whether the window it waits for was logged; cleared once DATA
+    // goes out again
+    stalled: bool,
+}
+
+// Send side of the connection. Flow control: DATA frames are only written
+// while both the connection and the stream window allow it, and otherwise
+// wait for the client's WINDOW_UPDATE frames. Response headers are encoded
+// with the connection's HPACK context. At most `burst_limit` DATA bytes are
+// produced before the driver takes the output, so that a huge response
+// goes out in slices between other work
+pub struct SendFlow {
+    pub connection_window: i64,
+    initial_window_size: u32,
+    max_frame_size: u32,
+    // Client's HPACK dynamic table size, bounds how response headers are encoded
+    header_table_size: u32,
+    // HPACK encoding context shared by all responses, mirroring the
+    // client's decoder. None once the client's table was shrunk to zero
+    encoder: Option<Encoder<'static>>,
+    pub sent_headers: HeaderSizes,
+    // Response body bytes sent in DATA frames
+    pub body_sent: u64,
+    // DATA bytes produced since the output was last taken
+    pub burst: usize,
+    burst_limit: usize,
+    // Lower stream ids, the older requests, are served first
+    pub streams: BTreeMap<u32, SendStream>,
+}
+
+impl SendFlow {
+    pub fn new(settings: &ServerSettings, burst_limit: usize) -> Self {
+        SendFlow {
+            connection_window: i64::from(DEFAULT_WINDOW_SIZE),
+            initial_window_size: settings.initial_window_size,
+            max_frame_size: settings.max_frame_size,
+            header_table_size: settings.header_table_size,
+            encoder: Some(Encoder::new()),
+            sent_headers: HeaderSizes::default(),
+            body_sent: 0,
+            burst: 0,
+            burst_limit,
+            streams: BTreeMap::new(),
+        }
+    }
+
+    // HPACK-encode a response header block. The hpack encoder assumes the
+    // default dynamic table size, so while the client allows less, blocks
+    // never touch the dynamic table instead
+    fn encode_header_block(&mut self, fields: &HeaderList) -> Vec<u8> {
+        let block = if self.header_table_size < DEFAULT_HEADER_TABLE_SIZE {
+            self.encoder = None;
+            encode_without_indexing(fields)
+        } else {
+            // After blocks that emptied the client's table, grow it back
+            // to the size a fresh encoder assumes
+            let mut block = Vec::new();
+            let encoder = self.encoder.get_or_insert_with(|| {
+                block = table_size_update(DEFAULT_HEADER_TABLE_SIZE as usize);
+                Encoder::new()
+            });
+            block.extend(encoder.encode(fields));
+            block
+        };
+
+        self.sent_headers.add(block.len(), fields);
+        block
+    }
+
+    // Start tracking a stream the client opened
+    pub fn open(&mut self, stream_id: u32) {
+        let window = i64::from(self.initial_window_size);
+        self.streams.entry(stream_id).or_insert(SendStream {
+            window,
+            body: None,
+            sent: 0,
+            reset_when_done: None,
+            stalled: false,
+        });
+    }
+
+    // Queue a response body behind its already sent headers
+    pub fn queue(&mut self, stream_id: u32, body: Body) {
+        self.open(stream_id);
+        if let Some(send) = self.streams.get_mut(&stream_id) {
+            send.body = Some(body);
+        }
+    }
+
+    pub fn close(&mut self, stream_id: u32) {
+        self.streams.remove(&stream_id);
+    }
+
+    // Pick up new client settings. Changing the initial window size moves
+    // the window of every open stream by the difference, which must not
+    // overflow any of them
+    pub fn apply_settings(&mut self, settings: &ServerSettings) -> Result<(), Http2Error> {
+        let delta = i64::from(settings.initial_window_size) - i64::from(self.initial_window_size);
+        for send in self.streams.values_mut() {
+            send.window += delta;
+        }
+        self.initial_window_size = settings.initial_window_size;
+        self.max_frame_size = settings.max_frame_size;
+        self.header_table_size = settings.header_table_size;
+
+        if i64::from(self.initial_window_size) > MAX_WINDOW_SIZE
+            || self.streams.values().any(|send| send.window > MAX_WINDOW_SIZE)
+        {
+            warning!("Initial window size {} overflows a flow-control window", self.initial_window_size);
+            return Err(Http2Error::Protocol(FLOW_CONTROL_ERROR));
+        }
+
+        Ok(())
+    }
+
+    // Reset the stream once its response is fully written
+    pub fn reset_when_done(
+        &mut self,
+        writer: &mut impl Write,
+        stream_id: u32,
+        error_code: ErrorCode,
+    ) -> Result<(), Http2Error> {
+        match self.streams.get_mut(&stream_id) {
+            Some(send) if send.body.is_some() => {
+                send.reset_when_done = Some(error_code);
+                Ok(())
+            }
+            _ => {
+                self.close(stream_id);
+                send_rst_stream(writer, stream_id, error_code)
+            }
+        }
+    }
+
+    // Write as much of the queued response bodies as the windows allow. A
+    // file body is read one DATA frame at a time; a stream whose file
+    // cannot be read is reset
+    pub fn send_pending(&mut self, writer: &mut impl Write) -> Result<(), Http2Error> {
+        let mut finished = Vec::new();
+        for (&stream_id, send) in self.streams.iter_mut() {
+            let body = match &mut send.body {
+                Some(body) => body,
+                None => continue,
+            };
+
+            let mut failed = false;
+            while send.sent < body.len() && self.burst < self.burst_limit {
+                let window = self.connection_window.min(send.window);
+                if window <= 0 {
+                    break;
+                }
+
+                let length = (body.len() - send.sent)
+                    .min(window as u64)
+                    .min(u64::from(self.max_frame_size))
+                    .min((self.burst_limit - self.burst) as u64) as usize;
+                let end = send.sent + length as u64;
+                let data_frame = FrameHeader {
+                    length: length as u32,
+                    type_: DATA_FRAME_TYPE,
+                    flags: if end == body.len() { END_STREAM_FLAG } else { 0 },
+                    stream_id,
+                };
+
+                let slice = match body.slice(send.sent, length) {
+                    Ok(slice) => slice,
+                    Err(e) => {
+                        eprintln!("Failed to read the response body of stream {}: {}", stream_id, e);
+                        failed = true;
+                        break;
+                    }
+                };
+                writer.write_all(&data_frame.to_bytes())?;
+                writer.write_all(&slice)?;
+
+                send.sent = end;
+                send.window -= length as i64;
+                self.connection_window -= length as i64;
+                self.body_sent += length as u64;
+                self.burst += length;
+                send.stalled = false;
+            }
+
+            if failed {
+                finished.push((stream_id, Some(INTERNAL_ERROR)));
+            } else if send.sent == body.len() {
+                finished.push((stream_id, send.reset_when_done));
+            } else if self.burst >= self.burst_limit {
+                // The rest follows once the driver took this slice
+                break;
+            } else if !send.stalled {
+                // Name the window that blocks the stream, once per stall.
+                // Either may sit at zero for as long as the client likes,
+                // and a stream window shrunk by SETTINGS_INITIAL_WINDOW_SIZE
+                // may even be negative
+                send.stalled = true;
+                let (blocking, window) = if send.window <= 0 {
+                    ("stream", send.window)
+                } else {
+                    ("connection", self.connection_window)
+                };
+                println!(
+                    "Stream {} waits for {} window space ({}), {} bytes left",
+                    stream_id,
+                    blocking,
+                    window,
+                    body.len() - send.sent
+                );
+            }
+        }
+
+        for (stream_id, reset) in finished {
+            self.close(stream_id);
+            if let Some(error_code) = reset {
+                send_rst_stream(writer, stream_id, error_code)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn send_ping(stream: &mut impl Write, data: &[u8; 8], ack: bool) -> Result<(), Http2Error> {
+    stream.write_all(&Frame::Ping { ack, data: *data }.serialize())?;
+    Ok(())
+}
+
+pub fn send_rst_stream(stream: &mut impl Write, stream_id: u32, error_code: ErrorCode) -> Result<(), Http2Error> {
+    println!("Sending RST_STREAM: stream_id={}, error_code={}", stream_id, error_code);
+
+    stream.write_all(&Frame::RstStream { stream_id, error_code }.serialize())?;
+    Ok(())
+}
+
+pub fn send_goaway(
+    stream: &mut impl Write,
+    last_stream_id: u32,
+    error_code: ErrorCode,
+    debug_data: &str,
+) -> Result<(), Http2Error> {
+    println!("Sending GOAWAY: last_stream_id={}, error_code={}", last_stream_id, error_code);
+
+    let goaway = Frame::Goaway {
+        last_stream_id,
+        error_code,
+        debug_data: debug_data.as_bytes().to_vec(),
+    };
+    stream.write_all(&goaway.serialize())?;
+    Ok(())
+}
+
+pub fn send_window_update(stream: &mut impl Write, stream_id: u32, increment: u32) -> Result<(), Http2Error> {
+    stream.write_all(&Frame::WindowUpdate { stream_id, increment }.serialize())?;
+    Ok(())
+}
+
+// Send the response headers and queue the body, which goes out as the
+// flow-control windows allow
+pub fn send_response(
+    stream: &mut impl Write,
+    flow: &mut SendFlow,
+    stream_id: u32,
+    response: Response,
+) -> Result<(), Http2Error> {
+    // HPACK-encode the header block, :status first as pseudo-headers
+    // must precede regular fields
+    let mut fields = vec![(b":status".to_vec(), response.status.to_string().into_bytes())];
+    for (name, value) in &response.headers {
+        fields.push((name.as_bytes().to_vec(), value.as_bytes().to_vec()));
+    }
+    let block = flow.encode_header_block(&fields);
+
+    // Send a HEADERS frame with the response headers, ending the stream
+    // right away when there is no body
+    let headers = Frame::Headers {
+        stream_id,
+        end_stream: response.body.is_empty(),
+        end_headers: true,
+        padding: None,
+        priority: None,
+        fragment: block,
+    };
+    stream.write_all(&headers.serialize())?;
+
+    if response.body.is_empty() {
+        flow.close(stream_id);
+        return Ok(());
+    }
+
+    flow.queue(stream_id, response.body);
+    flow.send_pending(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use hpack::Decoder;
+
+    use super::*;
+
+    #[test]
+    fn repeated_headers_go_out_in_order() {
+        let mut response = Response::new(200, b"ok".to_vec());
+        response.append("set-cookie", "a=1");
+        response.append("set-cookie", "b=2");
+        response.append("set-cookie", "a=1");
+
+        let mut output = Vec::new();
+        send_response(&mut output, &mut SendFlow::new(&ServerSettings::new(), usize::MAX), 1, response).unwrap();
+
+        let header = FrameHeader::from_bytes(output[..9].try_into().unwrap());
+        let block = &output[9..9 + header.length as usize];
+        let fields = Decoder::new().decode(block).unwrap();
+        let fields: Vec<(&[u8], &[u8])> = fields.iter().map(|(name, value)| (&name[..], &value[..])).collect();
+        assert_eq!(
+            fields,
+            [
+                (&b":status"[..], &b"200"[..]),
+                (b"content-length", b"2"),
+                (b"set-cookie", b"a=1"),
+                (b"set-cookie", b"b=2"),
+                (b"set-cookie", b"a=1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn response_headers_share_one_compression_context() {
+        let mut flow = SendFlow::new(&ServerSettings::new(), usize::MAX);
+        let mut decoder = Decoder::new();
+        let mut lengths = Vec::new();
+        for stream_id in [1, 3, 5] {
+            let mut response = Response::new(200, b"ok".to_vec());
+            response.set("x-served-by", "deepseek-http2");
+            let mut output = Vec::new();
+            send_response(&mut output, &mut flow, stream_id, response).unwrap();
+
+            let header = FrameHeader::from_bytes(output[..9].try_into().unwrap());
+            let block = &output[9..9 + header.length as usize];
+            lengths.push(block.len());
+            let fields = decoder.decode(block).unwrap();
+            assert!(fields.contains(&(b"x-served-by".to_vec(), b"deepseek-http2".to_vec())));
+        }
+
+        // Later blocks refer to the field the first one added to the table,
+        // which the client's single decoder still has
+        assert!(lengths[1] < lengths[0] && lengths[2] == lengths[1], "{:?}", lengths);
+    }
+
+    #[test]
+    fn bursts_stop_at_the_limit_and_resume_once_taken() {
+        let mut flow = SendFlow::new(&ServerSettings::new(), 1000);
+        let mut output = Vec::new();
+        send_response(&mut output, &mut flow, 1, Response::new(200, vec![b'x'; 2500])).unwrap();
+
+        let mut slices = Vec::new();
+        loop {
+            // The DATA frames after the response's HEADERS, or since the
+            // last slice was taken
+            let mut lengths = Vec::new();
+            let mut rest = &output[..];
+            while !rest.is_empty() {
+                let header = FrameHeader::from_bytes(rest[..9].try_into().unwrap());
+                if header.type_ == DATA_FRAME_TYPE {
+                    lengths.push(header.length);
+                }
+                rest = &rest[9 + header.length as usize..];
+            }
+            if lengths.is_empty() {
+                break;
+            }
+            slices.push(lengths);
+
+            output.clear();
+            flow.burst = 0;
+            flow.send_pending(&mut output).unwrap();
+        }
+        assert_eq!(slices, [[1000], [1000], [500]]);
+        assert_eq!(flow.body_sent, 2500);
+    }
+
+    #[test]
+    fn stalls_are_logged_once_until_data_moves() {
+        let mut flow = SendFlow::new(&ServerSettings::new(), usize::MAX);
+        flow.connection_window = 0;
+        let mut output = Vec::new();
+        send_response(&mut output, &mut flow, 1, Response::new(200, vec![b'x'; 100])).unwrap();
+        assert!(flow.streams[&1].stalled);
+
+        // Frames that leave the windows as they were do not unblock it
+        flow.send_pending(&mut output).unwrap();
+        assert!(flow.streams[&1].stalled);
+
+        // Some DATA goes out, then the stream waits again
+        flow.connection_window = 40;
+        flow.send_pending(&mut output).unwrap();
+        assert_eq!((flow.streams[&1].sent, flow.streams[&1].stalled), (40, true));
+        flow.connection_window = 60;
+        flow.send_pending(&mut output).unwrap();
+        assert!(!flow.streams.contains_key(&1));
+    }
+}
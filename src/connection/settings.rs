@@ -0,0 +1,171 @@
+// SETTINGS of both sides, and the frame limits they set
+
+use std::io::Write;
+
+use crate::config::ServerConfig;
+use crate::frame::{
+    Frame, FrameHeader, Http2Error, ACK_FLAG, CONTINUATION_FRAME_TYPE, DATA_FRAME_TYPE, GOAWAY_FRAME_TYPE,
+    HEADERS_FRAME_TYPE, PADDED_FLAG, PING_FRAME_TYPE, PRIORITY_FLAG, PRIORITY_FRAME_TYPE, PROTOCOL_ERROR,
+    RST_STREAM_FRAME_TYPE, SETTINGS_FRAME_TYPE, WINDOW_UPDATE_FRAME_TYPE,
+};
+use crate::warnings::warning;
+
+use super::headers::DEFAULT_HEADER_TABLE_SIZE;
+
+// Constants for settings keys
+pub const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x01;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x03;
+pub const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x04;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x05;
+const SETTINGS_ENABLE_PUSH: u16 = 0x02;
+const SETTINGS_NO_RFC7540_PRIORITIES: u16 = 0x09;
+
+// Largest frame payload the peer must accept before telling us otherwise
+const DEFAULT_MAX_FRAME_SIZE: u32 = 16384;
+
+// Largest frame payload SETTINGS_MAX_FRAME_SIZE may allow
+const MAX_ALLOWED_FRAME_SIZE: u32 = 16777215;
+
+// Flow-control window every stream and the connection start with
+pub const DEFAULT_WINDOW_SIZE: u32 = 65535;
+
+// SETTINGS values of one side of the connection: those the client sent
+// us, or the limits we advertise to it
+pub struct ServerSettings {
+    pub header_table_size: u32,
+    pub max_concurrent_streams: u32,
+    pub initial_window_size: u32,
+    pub max_frame_size: u32,
+    pub enable_push: bool,
+}
+
+impl ServerSettings {
+    pub fn new() -> Self {
+        ServerSettings {
+            header_table_size: DEFAULT_HEADER_TABLE_SIZE, // Default value
+            max_concurrent_streams: u32::MAX, // Default value: unlimited
+            initial_window_size: DEFAULT_WINDOW_SIZE, // Default value
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,   // Default value
+            enable_push: true,           // Default value
+        }
+    }
+
+    pub fn update(&mut self, key: u16, value: u32) {
+        match key {
+            SETTINGS_HEADER_TABLE_SIZE => {
+                self.header_table_size = value;
+                println!("Updated header_table_size to {}", value);
+            }
+            SETTINGS_MAX_CONCURRENT_STREAMS => {
+                self.max_concurrent_streams = value;
+                println!("Updated max_concurrent_streams to {}", value);
+            }
+            SETTINGS_INITIAL_WINDOW_SIZE => {
+                self.initial_window_size = value;
+                println!("Updated initial_window_size to {}", value);
+            }
+            SETTINGS_MAX_FRAME_SIZE if (DEFAULT_MAX_FRAME_SIZE..=MAX_ALLOWED_FRAME_SIZE).contains(&value) => {
+                self.max_frame_size = value;
+                println!("Updated max_frame_size to {}", value);
+            }
+            SETTINGS_MAX_FRAME_SIZE => {
+                println!("Ignoring invalid max_frame_size: {}", value);
+            }
+            SETTINGS_ENABLE_PUSH => {
+                self.enable_push = value != 0;
+                println!("Updated enable_push to {}", value != 0);
+            }
+            _ => {
+                println!("Ignoring unknown setting: key={}, value={}", key, value);
+            }
+        }
+    }
+}
+
+// Check a frame header against our limits and the fixed layout of its
+// frame type, before any of its payload is read
+pub fn validate_frame_header(header: &FrameHeader, local_settings: &ServerSettings) -> Result<(), Http2Error> {
+    if header.length > local_settings.max_frame_size {
+        warning!("Frame of {} bytes exceeds our limit of {}", header.length, local_settings.max_frame_size);
+        return Err(Http2Error::FrameSize);
+    }
+
+    // Whether the frame belongs to the connection rather than a stream,
+    // and whether its length is valid
+    let padded = header.flags & PADDED_FLAG == PADDED_FLAG;
+    let (connection_level, valid_length) = match header.type_ {
+        DATA_FRAME_TYPE => (false, !padded || header.length >= 1),
+        HEADERS_FRAME_TYPE => {
+            let mut minimum = 0;
+            if padded {
+                minimum += 1;
+            }
+            if header.flags & PRIORITY_FLAG == PRIORITY_FLAG {
+                minimum += 5;
+            }
+            (false, header.length >= minimum)
+        }
+        PRIORITY_FRAME_TYPE => (false, header.length == 5),
+        RST_STREAM_FRAME_TYPE => (false, header.length == 4),
+        SETTINGS_FRAME_TYPE if header.flags & ACK_FLAG == ACK_FLAG => (true, header.length == 0),
+        SETTINGS_FRAME_TYPE => (true, header.length.is_multiple_of(6)),
+        PING_FRAME_TYPE => (true, header.length == 8),
+        GOAWAY_FRAME_TYPE => (true, header.length >= 8),
+        WINDOW_UPDATE_FRAME_TYPE => {
+            // The only frame type valid both on a stream and on the connection
+            if header.length != 4 {
+                warning!("WINDOW_UPDATE frame with invalid length {}", header.length);
+                return Err(Http2Error::FrameSize);
+            }
+            return Ok(());
+        }
+        CONTINUATION_FRAME_TYPE => (false, true),
+        _ => return Ok(()),
+    };
+
+    if connection_level != (header.stream_id == 0) {
+        warning!("Frame type {} on stream {}", header.type_, header.stream_id);
+        return Err(Http2Error::Protocol(PROTOCOL_ERROR));
+    }
+    if !valid_length {
+        warning!("Frame type {} with invalid length {}", header.type_, header.length);
+        return Err(Http2Error::FrameSize);
+    }
+
+    Ok(())
+}
+
+pub fn send_http2_settings_frame(
+    stream: &mut impl Write,
+    config: &ServerConfig,
+    local_settings: &ServerSettings,
+) -> Result<(), Http2Error> {
+    let mut settings = vec![
+        (SETTINGS_MAX_CONCURRENT_STREAMS, local_settings.max_concurrent_streams),
+        (SETTINGS_MAX_FRAME_SIZE, local_settings.max_frame_size),
+    ];
+    if !config.spec_level.honors_rfc7540_priorities() {
+        settings.push((SETTINGS_NO_RFC7540_PRIORITIES, 1));
+    }
+
+    stream.write_all(&Frame::Settings { ack: false, settings }.serialize())?;
+    Ok(())
+}
+
+// Apply the client's settings, and acknowledge them right away
+pub fn apply_client_settings(
+    writer: &mut impl Write,
+    settings: &mut ServerSettings,
+    pairs: &[(u16, u32)],
+) -> Result<(), Http2Error> {
+    update_settings(settings, pairs);
+    writer.write_all(&Frame::Settings { ack: true, settings: Vec::new() }.serialize())?;
+    Ok(())
+}
+
+pub fn update_settings(settings: &mut ServerSettings, pairs: &[(u16, u32)]) {
+    for &(key, value) in pairs {
+        println!("Setting: key={}, value={}", key, value);
+        settings.update(key, value);
+    }
+}
@@ -0,0 +1,534 @@
+// Receive side of the streams: request header blocks and bodies, and
+// the stream state they leave behind
+
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+
+use crate::config::{MalformedPolicy, ServerConfig};
+use crate::frame::{
+    ErrorCode, FrameHeader, Http2Error, PrioritySpec, END_STREAM_FLAG, FLOW_CONTROL_ERROR, NO_ERROR, PROTOCOL_ERROR,
+    REFUSED_STREAM, STREAM_CLOSED,
+};
+use crate::message::{HeaderList, Request, Response};
+use crate::routes::handle_request;
+use crate::warnings::warning;
+
+use super::send::{send_response, send_rst_stream, send_window_update, SendFlow, MAX_WINDOW_SIZE};
+use super::settings::DEFAULT_WINDOW_SIZE;
+
+// Largest unread request body we drain instead of resetting the stream
+const MAX_DRAIN_BYTES: u64 = 64 * 1024;
+
+// How many of the streams we reset are remembered, so that the frames the
+// client sent before it saw the reset are ignored
+const RECENTLY_RESET_STREAMS: usize = 128;
+
+// Highest stream id; a client that used it needs a new connection
+pub const MAX_STREAM_ID: u32 = 0x7fff_ffff;
+
+// Header block of a HEADERS frame, completed by any CONTINUATION frames
+pub struct HeaderBlock {
+    pub stream_id: u32,
+    pub end_stream: bool,
+    pub priority: Option<PrioritySpec>,
+    pub fragments: Vec<u8>,
+}
+
+// State of a stream the client may still send frames on
+pub enum StreamState {
+    // Request headers received, body still arriving
+    Open {
+        request: Request,
+        content_length: Option<u64>,
+    },
+    // Answered early; the rest of the body is discarded, up to this many bytes
+    Draining(u64),
+}
+
+// Streams the client may still send frames on. Streams closed in both
+// directions are removed, so this never holds more than the concurrency
+// limit allows; only the ids of the latest streams we reset are kept on
+pub struct Streams {
+    states: HashMap<u32, StreamState>,
+    // Draining streams among `states`, which unlike open ones no longer
+    // have a response in progress
+    draining: usize,
+    // Oldest first, at most RECENTLY_RESET_STREAMS of them
+    recently_reset: VecDeque<u32>,
+}
+
+impl Streams {
+    pub fn new() -> Self {
+        Streams {
+            states: HashMap::new(),
+            draining: 0,
+            recently_reset: VecDeque::new(),
+        }
+    }
+
+    pub fn contains_key(&self, stream_id: &u32) -> bool {
+        self.states.contains_key(stream_id)
+    }
+
+    pub fn insert(&mut self, stream_id: u32, state: StreamState) {
+        if matches!(state, StreamState::Draining(_)) {
+            self.draining += 1;
+        }
+        if let Some(StreamState::Draining(_)) = self.states.insert(stream_id, state) {
+            self.draining -= 1;
+        }
+    }
+
+    pub fn remove(&mut self, stream_id: &u32) -> Option<StreamState> {
+        let state = self.states.remove(stream_id);
+        if let Some(StreamState::Draining(_)) = state {
+            self.draining -= 1;
+        }
+        state
+    }
+
+    // Forget a stream we reset while the client may still send on it,
+    // remembering only that its frames are to be ignored
+    pub fn reset(&mut self, stream_id: u32) {
+        self.remove(&stream_id);
+        if self.recently_reset.len() == RECENTLY_RESET_STREAMS {
+            self.recently_reset.pop_front();
+        }
+        self.recently_reset.push_back(stream_id);
+    }
+
+    // Whether frames on a stream that is not open any more come from
+    // before the client saw our reset. Older resets are forgotten, and
+    // their frames then count as frames on a closed stream
+    pub fn was_reset(&self, stream_id: u32) -> bool {
+        self.recently_reset.contains(&stream_id)
+    }
+
+    pub fn draining(&self) -> usize {
+        self.draining
+    }
+
+    pub fn receiving(&self) -> impl Iterator<Item = u32> + '_ {
+        let open = self.states.iter().filter(|(_, state)| matches!(state, StreamState::Open { .. }));
+        open.map(|(&stream_id, _)| stream_id)
+    }
+
+    pub fn clear(&mut self) {
+        self.states.clear();
+        self.draining = 0;
+        self.recently_reset.clear();
+    }
+}
+
+// Credit a WINDOW_UPDATE to the connection or stream window, then send
+// whatever response data it unblocks
+pub fn handle_window_update(
+    writer: &mut impl Write,
+    streams: &mut Streams,
+    flow: &mut SendFlow,
+    stream_id: u32,
+    increment: u32,
+    highest_stream_id: u32,
+) -> Result<(), Http2Error> {
+    if stream_id == 0 {
+        if increment == 0 {
+            warning!("WINDOW_UPDATE with a zero increment on the connection");
+            return Err(Http2Error::Protocol(PROTOCOL_ERROR));
+        }
+
+        flow.connection_window += i64::from(increment);
+        if flow.connection_window > MAX_WINDOW_SIZE {
+            warning!("Connection window overflowed");
+            return Err(Http2Error::Protocol(FLOW_CONTROL_ERROR));
+        }
+    } else if stream_id > highest_stream_id {
+        warning!("WINDOW_UPDATE frame on idle stream {}", stream_id);
+        return Err(Http2Error::Protocol(PROTOCOL_ERROR));
+    } else if let Some(send) = flow.streams.get_mut(&stream_id) {
+        send.window += i64::from(increment);
+
+        let error_code = if increment == 0 {
+            warning!("WINDOW_UPDATE with a zero increment on stream {}", stream_id);
+            Some(PROTOCOL_ERROR)
+        } else if send.window > MAX_WINDOW_SIZE {
+            warning!("Window of stream {} overflowed", stream_id);
+            Some(FLOW_CONTROL_ERROR)
+        } else {
+            None
+        };
+
+        if let Some(error_code) = error_code {
+            let end_stream = !streams.contains_key(&stream_id);
+            reset_stream(writer, streams, flow, stream_id, error_code, end_stream)?;
+        }
+    }
+    // Otherwise the stream is closed; a WINDOW_UPDATE may still arrive
+    // shortly after that and is ignored
+
+    flow.send_pending(writer)
+}
+
+// Whether a new stream is accepted: not once a shutdown stopped taking new
+// streams, and not beyond our concurrency limit. The limit applies as soon
+// as it is advertised; refused streams can simply be retried
+pub fn may_open_stream(
+    config: &ServerConfig,
+    streams: &Streams,
+    flow: &SendFlow,
+    stream_id: u32,
+    last_stream_id: u32,
+) -> bool {
+    if stream_id > last_stream_id {
+        println!("Not accepting stream {} while shutting down", stream_id);
+        return false;
+    }
+
+    // Streams still sending a response or receiving a request body, and
+    // the ones answered while we drain their body
+    let open = flow.streams.len() + streams.draining();
+    if open >= config.max_concurrent_streams as usize {
+        println!("Not accepting stream {}: {} streams already open", stream_id, open);
+        return false;
+    }
+
+    true
+}
+
+// Parse the content-length header(s) of a request.
+// Repeated fields are only accepted when they all carry the same value.
+fn parse_content_length(headers: &HeaderList) -> Result<Option<u64>, &'static str> {
+    let mut content_length = None;
+
+    for (name, value) in headers {
+        if name.as_slice() != b"content-length" {
+            continue;
+        }
+
+        if value.is_empty() || !value.iter().all(|b| b.is_ascii_digit()) {
+            return Err("content-length is not a decimal number");
+        }
+
+        let mut parsed: u64 = 0;
+        for digit in value {
+            parsed = parsed
+                .checked_mul(10)
+                .and_then(|n| n.checked_add(u64::from(digit - b'0')))
+                .ok_or("content-length overflows u64")?;
+        }
+
+        match content_length {
+            Some(previous) if previous != parsed => {
+                return Err("conflicting content-length values");
+            }
+            _ => content_length = Some(parsed),
+        }
+    }
+
+    Ok(content_length)
+}
+
+// Reset a stream, and remember it if the client can still send on it
+pub fn reset_stream(
+    stream: &mut impl Write,
+    streams: &mut Streams,
+    flow: &mut SendFlow,
+    stream_id: u32,
+    error_code: ErrorCode,
+    end_stream: bool,
+) -> Result<(), Http2Error> {
+    send_rst_stream(stream, stream_id, error_code)?;
+
+    flow.close(stream_id);
+    if end_stream {
+        streams.remove(&stream_id);
+    } else {
+        streams.reset(stream_id);
+    }
+
+    Ok(())
+}
+
+// Act on a complete, decoded request header block: validate it, build the
+// request and send the response. Malformed requests only fail their stream
+pub fn handle_header_block(
+    config: &ServerConfig,
+    writer: &mut impl Write,
+    streams: &mut Streams,
+    flow: &mut SendFlow,
+    block: HeaderBlock,
+    headers: HeaderList,
+    accept_new_stream: bool,
+) -> Result<(), Http2Error> {
+    let stream_id = block.stream_id;
+    let end_stream = block.end_stream;
+
+    // Trailers the client sent before it saw our reset
+    if streams.was_reset(stream_id) {
+        println!("Ignoring header block on reset stream {}", stream_id);
+        return Ok(());
+    }
+
+    if let Some(spec) = &block.priority {
+        // A stream cannot depend on itself
+        if spec.dependency == stream_id {
+            warning!("Stream {} depends on itself", stream_id);
+            return reset_stream(writer, streams, flow, stream_id, PROTOCOL_ERROR, end_stream);
+        }
+
+        if config.spec_level.honors_rfc7540_priorities() {
+            println!(
+                "Priority: exclusive={}, dependency={}, weight={}",
+                spec.exclusive, spec.dependency, spec.weight
+            );
+        }
+    }
+
+    // A header block on a stream that is already open carries the
+    // request trailers, which may legitimately be empty
+    if let Some(state) = streams.remove(&stream_id) {
+        println!("Received {} trailer fields on stream {}", headers.len(), stream_id);
+        return match state {
+            StreamState::Open { request, content_length } if end_stream => {
+                complete_request(config, writer, streams, flow, stream_id, request, content_length)
+            }
+            StreamState::Open { .. } => {
+                reject_malformed(config, writer, streams, flow, stream_id, "trailers without END_STREAM", end_stream)
+            }
+            StreamState::Draining(budget) => {
+                if !end_stream {
+                    streams.insert(stream_id, StreamState::Draining(budget));
+                }
+                Ok(())
+            }
+        };
+    }
+
+    if !accept_new_stream {
+        println!("Refusing stream {}", stream_id);
+        return reset_stream(writer, streams, flow, stream_id, REFUSED_STREAM, end_stream);
+    }
+
+    flow.open(stream_id);
+
+    // A malformed header block (including an empty one, which
+    // lacks the mandatory pseudo-headers) is a stream error
+    let request = match Request::from_headers(&headers, config.spec_level) {
+        Ok(request) => request,
+        Err(reason) => return reject_malformed(config, writer, streams, flow, stream_id, reason, end_stream),
+    };
+
+    // Refuse bad or oversized bodies before any DATA is read
+    let content_length = match parse_content_length(&headers) {
+        Ok(Some(length)) if length > config.max_body_size => {
+            warning!("Declared content-length {} exceeds limit of {}", length, config.max_body_size);
+            let response = Response::text(413, "Payload Too Large");
+            if end_stream {
+                return send_response(writer, flow, stream_id, response);
+            }
+            return respond_early(writer, streams, flow, stream_id, response, Some(length), DEFAULT_WINDOW_SIZE);
+        }
+        Ok(content_length) => content_length,
+        Err(reason) => return reject_malformed(config, writer, streams, flow, stream_id, reason, end_stream),
+    };
+
+    if end_stream {
+        return complete_request(config, writer, streams, flow, stream_id, request, content_length);
+    }
+
+    // Wait for the body
+    streams.insert(stream_id, StreamState::Open { request, content_length });
+    Ok(())
+}
+
+// Act on a DATA frame's payload
+pub fn handle_data_frame(
+    config: &ServerConfig,
+    writer: &mut impl Write,
+    streams: &mut Streams,
+    flow: &mut SendFlow,
+    header: &FrameHeader,
+    data: Vec<u8>,
+) -> Result<(), Http2Error> {
+    let stream_id = header.stream_id;
+    let end_stream = header.flags & END_STREAM_FLAG == END_STREAM_FLAG;
+
+    let state = match streams.remove(&stream_id) {
+        Some(state) => state,
+        // Frames already in flight when we reset the stream
+        None if streams.was_reset(stream_id) => return Ok(()),
+        // The client ended the stream before; the response still being
+        // sent is dropped with it
+        None => {
+            warning!("DATA frame on closed stream {}", stream_id);
+            return reset_stream(writer, streams, flow, stream_id, STREAM_CLOSED, end_stream);
+        }
+    };
+
+    match state {
+        StreamState::Open { mut request, content_length } => {
+            request.body.extend_from_slice(&data);
+            let received = request.body.len() as u64;
+
+            if received > config.max_body_size {
+                warning!("Request body on stream {} exceeds limit of {}", stream_id, config.max_body_size);
+                let response = Response::text(413, "Payload Too Large");
+                if end_stream {
+                    return send_response(writer, flow, stream_id, response);
+                }
+                // Earlier DATA was credited back to the stream window, this
+                // frame was not
+                let remaining = content_length.map(|length| length.saturating_sub(received));
+                let window = DEFAULT_WINDOW_SIZE.saturating_sub(header.length);
+                return respond_early(writer, streams, flow, stream_id, response, remaining, window);
+            }
+
+            if content_length.is_some_and(|length| received > length) {
+                let reason = "body longer than content-length";
+                return reject_malformed(config, writer, streams, flow, stream_id, reason, end_stream);
+            }
+
+            if end_stream {
+                return complete_request(config, writer, streams, flow, stream_id, request, content_length);
+            }
+
+            // The body was consumed, let the client send more
+            streams.insert(stream_id, StreamState::Open { request, content_length });
+            if header.length > 0 {
+                send_window_update(writer, stream_id, header.length)?;
+            }
+            Ok(())
+        }
+        StreamState::Draining(budget) => {
+            // Discard the remaining upload of a stream we already answered
+            println!("Discarded {} DATA bytes on stream {}", header.length, stream_id);
+            if u64::from(header.length) > budget {
+                println!("Drain limit exceeded on stream {}", stream_id);
+                if !end_stream {
+                    streams.reset(stream_id);
+                }
+                return flow.reset_when_done(writer, stream_id, NO_ERROR);
+            }
+            if !end_stream {
+                streams.insert(stream_id, StreamState::Draining(budget - u64::from(header.length)));
+            }
+            Ok(())
+        }
+    }
+}
+
+// Respond to a request whose body has been fully received
+fn complete_request(
+    config: &ServerConfig,
+    writer: &mut impl Write,
+    streams: &mut Streams,
+    flow: &mut SendFlow,
+    stream_id: u32,
+    request: Request,
+    content_length: Option<u64>,
+) -> Result<(), Http2Error> {
+    if content_length.is_some_and(|length| length != request.body.len() as u64) {
+        let reason = "body does not match content-length";
+        return reject_malformed(config, writer, streams, flow, stream_id, reason, true);
+    }
+
+    let response = handle_request(config, &request);
+    send_response(writer, flow, stream_id, response)
+}
+
+// Reject a malformed request, which is a stream error. Under the respond
+// policy the client first gets a 400 response naming the problem, and a
+// stream it is still sending on is reset with PROTOCOL_ERROR after it.
+// Malformed requests are always caught before their response went out
+fn reject_malformed(
+    config: &ServerConfig,
+    writer: &mut impl Write,
+    streams: &mut Streams,
+    flow: &mut SendFlow,
+    stream_id: u32,
+    reason: &str,
+    end_stream: bool,
+) -> Result<(), Http2Error> {
+    warning!("Malformed request on stream {}: {}", stream_id, reason);
+    if config.malformed_policy == MalformedPolicy::Reset {
+        return reset_stream(writer, streams, flow, stream_id, PROTOCOL_ERROR, end_stream);
+    }
+
+    send_response(writer, flow, stream_id, Response::text(400, &format!("Bad Request: {}", reason)))?;
+    if end_stream {
+        return Ok(());
+    }
+    streams.reset(stream_id);
+    flow.reset_when_done(writer, stream_id, PROTOCOL_ERROR)
+}
+
+// Send a response before the request body is complete. A small remainder
+// is cheaper to drain; otherwise ask the client to stop and ignore
+// whatever DATA is already in flight. A draining stream gets no more
+// WINDOW_UPDATE, so only a remainder that fits in what is left of its
+// `window` can arrive at all
+fn respond_early(
+    writer: &mut impl Write,
+    streams: &mut Streams,
+    flow: &mut SendFlow,
+    stream_id: u32,
+    response: Response,
+    remaining: Option<u64>,
+    window: u32,
+) -> Result<(), Http2Error> {
+    send_response(writer, flow, stream_id, response)?;
+
+    // Only a stream whose response is out drains; the others are counted
+    // as open until their reset
+    let answered = !flow.streams.contains_key(&stream_id);
+    let limit = MAX_DRAIN_BYTES.min(u64::from(window));
+    match remaining {
+        Some(remaining) if remaining <= limit && answered => {
+            println!("Draining up to {} body bytes on stream {}", remaining, stream_id);
+            streams.insert(stream_id, StreamState::Draining(remaining));
+            Ok(())
+        }
+        _ => {
+            streams.reset(stream_id);
+            flow.reset_when_done(writer, stream_id, NO_ERROR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content_length(values: &[&str]) -> Result<Option<u64>, &'static str> {
+        let headers = values.iter().map(|value| (b"content-length".to_vec(), value.as_bytes().to_vec()));
+        parse_content_length(&headers.collect())
+    }
+
+    #[test]
+    fn content_length_is_one_decimal_number() {
+        assert_eq!(content_length(&[]), Ok(None));
+        assert_eq!(content_length(&["0"]), Ok(Some(0)));
+        assert_eq!(content_length(&["1024", "1024"]), Ok(Some(1024)));
+        assert_eq!(content_length(&["18446744073709551615"]), Ok(Some(u64::MAX)));
+
+        assert_eq!(content_length(&["1024", "1025"]), Err("conflicting content-length values"));
+        assert_eq!(content_length(&["18446744073709551616"]), Err("content-length overflows u64"));
+        for value in ["", "+5", "-5", "5x", " 5", "0x10"] {
+            assert_eq!(content_length(&[value]), Err("content-length is not a decimal number"), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn reset_streams_are_not_kept() {
+        let mut streams = Streams::new();
+        for stream_id in (1..10_000).step_by(2) {
+            streams.insert(stream_id, StreamState::Draining(MAX_DRAIN_BYTES));
+            assert_eq!(streams.draining(), 1);
+            streams.reset(stream_id);
+            assert!(streams.states.is_empty());
+            assert_eq!(streams.draining(), 0);
+            assert!(streams.recently_reset.len() <= RECENTLY_RESET_STREAMS);
+        }
+        assert!(streams.was_reset(9999));
+        assert!(!streams.was_reset(9999 - 2 * RECENTLY_RESET_STREAMS as u32));
+    }
+}
@@ -0,0 +1,1439 @@
+// Connections served over loopback TCP: each client talks to
+// `handle_client` running in a thread of its own
+
+use std::fs;
+use std::io::{ErrorKind, Read};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::{RwLock, RwLockReadGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use hpack::{Decoder, Encoder};
+
+use super::*;
+use crate::config::{
+    IoModel, MalformedPolicy, SpecLevel, DEFAULT_DRAIN_TIMEOUT, DEFAULT_IDLE_TIMEOUT, DEFAULT_KEEPALIVE_INTERVAL,
+    DEFAULT_MAX_BODY_SIZE, DEFAULT_MAX_CONCURRENT_STREAMS, DEFAULT_MAX_FRAMES, DEFAULT_MAX_FRAME_RATE,
+    DEFAULT_WRITE_BUDGET,
+};
+use crate::frame::{
+    COMPRESSION_ERROR, DATA_FRAME_TYPE, FLOW_CONTROL_ERROR, FRAME_SIZE_ERROR, GOAWAY_FRAME_TYPE, HEADERS_FRAME_TYPE,
+    PADDED_FLAG, PING_FRAME_TYPE, PRIORITY_FLAG, PRIORITY_FRAME_TYPE, PUSH_PROMISE_FRAME_TYPE, REFUSED_STREAM,
+    RST_STREAM_FRAME_TYPE, STREAM_CLOSED, WINDOW_UPDATE_FRAME_TYPE,
+};
+use crate::handle_client;
+use crate::message::HeaderList;
+use crate::shutdown::SHUTDOWN;
+use headers::DEFAULT_HEADER_TABLE_SIZE;
+use settings::{SETTINGS_HEADER_TABLE_SIZE, SETTINGS_INITIAL_WINDOW_SIZE};
+
+const ACK: u8 = 0x01;
+const END_STREAM: u8 = 0x01;
+const END_HEADERS: u8 = 0x04;
+
+// Error code of a client giving up on a stream
+const CANCEL: u32 = 0x08;
+
+// A frame as it arrived from the server
+type Received = (FrameHeader, Vec<u8>);
+
+// A shutdown request reaches every connection in the process, so tests
+// that make one hold this exclusively while other clients share it
+static CONNECTIONS: RwLock<()> = RwLock::new(());
+
+struct Client {
+    stream: TcpStream,
+    server: JoinHandle<()>,
+    // Received from the server, but not taken yet
+    received: Vec<u8>,
+    // Decodes the header blocks of the responses, in the order they came
+    decoder: Decoder<'static>,
+    header_lists: Vec<(u32, HeaderList)>,
+    _shared: Option<RwLockReadGuard<'static, ()>>,
+}
+
+impl Client {
+    // A client that sent the preface and its SETTINGS
+    fn new() -> Self {
+        Client::with_config(config(SpecLevel::Rfc9113))
+    }
+
+    fn with_config(config: ServerConfig) -> Self {
+        Client::open(config, &[])
+    }
+
+    // A client that opens the connection with the given settings
+    fn open(config: ServerConfig, settings: &[(u16, u32)]) -> Self {
+        let mut client = Client::connect(config);
+        client.start_http2(settings);
+        client
+    }
+
+    // A client that sent nothing yet
+    fn connect(config: ServerConfig) -> Self {
+        let shared = CONNECTIONS.read().unwrap_or_else(|e| e.into_inner());
+        Client::serve(config, Some(shared))
+    }
+
+    // A connection to a server of its own, served while `shared` is held
+    fn serve(config: ServerConfig, shared: Option<RwLockReadGuard<'static, ()>>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_client(stream, config);
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        // A server that stops answering fails the test instead of hanging it
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        Client {
+            stream,
+            server,
+            received: Vec::new(),
+            decoder: Decoder::new(),
+            header_lists: Vec::new(),
+            _shared: shared,
+        }
+    }
+
+    fn start_http2(&mut self, settings: &[(u16, u32)]) {
+        self.send(CONNECTION_PREFACE);
+        self.send(&settings_frame(settings));
+    }
+
+    fn send(&mut self, bytes: &[u8]) {
+        self.stream.write_all(bytes).unwrap();
+    }
+
+    // HEADERS for a request, in a single frame
+    fn request(&mut self, stream_id: u32, method: &str, path: &str, fields: &[(&str, &str)], end_stream: bool) {
+        let flags = if end_stream { END_HEADERS | END_STREAM } else { END_HEADERS };
+        self.send(&frame(HEADERS_FRAME_TYPE, flags, stream_id, &request_block(method, path, fields)));
+    }
+
+    fn data(&mut self, stream_id: u32, data: &[u8], end_stream: bool) {
+        let flags = if end_stream { END_STREAM } else { 0 };
+        self.send(&frame(DATA_FRAME_TYPE, flags, stream_id, data));
+    }
+
+    // Wait for more from the server; false once it closed the connection
+    fn receive(&mut self) -> bool {
+        let mut buffer = [0; 16384];
+        match self.stream.read(&mut buffer) {
+            Ok(0) => false,
+            Ok(n) => {
+                self.received.extend_from_slice(&buffer[..n]);
+                true
+            }
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => false,
+            Err(e) => panic!("Failed to read from the server: {}", e),
+        }
+    }
+
+    // Wait for at least `length` bytes from the server, and take them
+    fn bytes(&mut self, length: usize) -> Vec<u8> {
+        while self.received.len() < length && self.receive() {}
+        self.received.drain(..length.min(self.received.len())).collect()
+    }
+
+    // The next frame the server sent, or None once it closed the connection
+    fn next_frame(&mut self) -> Option<Received> {
+        loop {
+            if self.received.len() >= 9 {
+                let header = FrameHeader::from_bytes(self.received[..9].try_into().unwrap());
+                let end = 9 + header.length as usize;
+                if self.received.len() >= end {
+                    let payload = self.received[9..end].to_vec();
+                    self.received.drain(..end);
+                    if header.type_ == HEADERS_FRAME_TYPE {
+                        let fields = self.decoder.decode(&payload).expect("undecodable header block");
+                        self.header_lists.push((header.stream_id, fields));
+                    }
+                    return Some((header, payload));
+                }
+            }
+            if !self.receive() {
+                return None;
+            }
+        }
+    }
+
+    // Frames up to and including the first one of type `type_`
+    fn frames_until(&mut self, type_: u8) -> Vec<Received> {
+        let mut frames = Vec::new();
+        while frames.last().is_none_or(|(header, _): &Received| header.type_ != type_) {
+            frames.push(self.next_frame().expect("connection closed"));
+        }
+        frames
+    }
+
+    // Frames up to and including the end of the response on `stream_id`
+    fn response(&mut self, stream_id: u32) -> Vec<Received> {
+        let mut frames = Vec::new();
+        loop {
+            let (header, payload) = self.next_frame().expect("connection closed");
+            let ends_response = matches!(header.type_, HEADERS_FRAME_TYPE | DATA_FRAME_TYPE)
+                && header.stream_id == stream_id
+                && header.flags & END_STREAM == END_STREAM;
+            frames.push((header, payload));
+            if ends_response {
+                return frames;
+            }
+        }
+    }
+
+    // The :status of the response on `stream_id`, once it arrived
+    fn status(&self, stream_id: u32) -> Option<&[u8]> {
+        self.field(stream_id, ":status")
+    }
+
+    // The first field called `name` in the response on `stream_id`
+    fn field(&self, stream_id: u32, name: &str) -> Option<&[u8]> {
+        let (_, fields) = self.header_lists.iter().find(|(id, _)| *id == stream_id)?;
+        fields.iter().find(|(field, _)| field == name.as_bytes()).map(|(_, value)| value.as_slice())
+    }
+
+    // Close our side of the connection, and read what the server still
+    // sends until it closes its side too
+    fn finish(mut self) -> Vec<Received> {
+        // Fails when the server already reset the connection
+        let _ = self.stream.shutdown(Shutdown::Write);
+        let mut frames = Vec::new();
+        while let Some(frame) = self.next_frame() {
+            frames.push(frame);
+        }
+        self.server.join().unwrap();
+        frames
+    }
+}
+
+fn config(spec_level: SpecLevel) -> ServerConfig {
+    ServerConfig {
+        spec_level,
+        max_body_size: DEFAULT_MAX_BODY_SIZE,
+        keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
+        drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+        root: None,
+        max_frames: DEFAULT_MAX_FRAMES,
+        max_frame_rate: DEFAULT_MAX_FRAME_RATE,
+        max_concurrent_streams: DEFAULT_MAX_CONCURRENT_STREAMS,
+        io_model: IoModel::Threads,
+        idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        malformed_policy: MalformedPolicy::Respond,
+        server_timing: false,
+        write_budget: DEFAULT_WRITE_BUDGET,
+    }
+}
+
+fn frame(type_: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+    frame.extend([type_, flags]);
+    frame.extend(stream_id.to_be_bytes());
+    frame.extend(payload);
+    frame
+}
+
+fn settings_frame(settings: &[(u16, u32)]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    for (key, value) in settings {
+        payload.extend_from_slice(&key.to_be_bytes());
+        payload.extend_from_slice(&value.to_be_bytes());
+    }
+    frame(SETTINGS_FRAME_TYPE, 0, 0, &payload)
+}
+
+fn window_update(stream_id: u32, increment: u32) -> Vec<u8> {
+    frame(WINDOW_UPDATE_FRAME_TYPE, 0, stream_id, &increment.to_be_bytes())
+}
+
+// Header block of a request, encoded on its own
+fn request_block(method: &str, path: &str, fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut headers = vec![(":method", method), (":scheme", "http"), (":path", path), (":authority", "localhost")];
+    headers.extend(fields);
+    let headers = headers.iter().map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()));
+    Encoder::new().encode(&headers.collect())
+}
+
+// Error codes of the RST_STREAM frames among `frames`, by stream
+fn resets(frames: &[Received]) -> Vec<(u32, u32)> {
+    let resets = frames.iter().filter(|(header, _)| header.type_ == RST_STREAM_FRAME_TYPE);
+    resets.map(|(header, payload)| (header.stream_id, u32::from_be_bytes(payload[..4].try_into().unwrap()))).collect()
+}
+
+fn goaway(frames: &[Received]) -> Option<(u32, u32)> {
+    let (_, payload) = frames.iter().find(|(header, _)| header.type_ == GOAWAY_FRAME_TYPE)?;
+    let last_stream_id = u32::from_be_bytes(payload[..4].try_into().unwrap());
+    Some((last_stream_id, u32::from_be_bytes(payload[4..8].try_into().unwrap())))
+}
+
+fn window_updates(frames: &[Received]) -> Vec<(u32, u32)> {
+    let updates = frames.iter().filter(|(header, _)| header.type_ == WINDOW_UPDATE_FRAME_TYPE);
+    updates.map(|(header, payload)| (header.stream_id, u32::from_be_bytes(payload[..4].try_into().unwrap()))).collect()
+}
+
+fn responses(frames: &[Received]) -> usize {
+    frames.iter().filter(|(header, _)| header.type_ == HEADERS_FRAME_TYPE).count()
+}
+
+// Lengths of the DATA frames among `frames`
+fn data_lengths(frames: &[Received]) -> Vec<usize> {
+    let data = frames.iter().filter(|(header, _)| header.type_ == DATA_FRAME_TYPE);
+    data.map(|(_, payload)| payload.len()).collect()
+}
+
+// The body sent on `stream_id`, from its DATA frames among `frames`
+fn body(frames: &[Received], stream_id: u32) -> Vec<u8> {
+    let data = frames.iter().filter(|(header, _)| header.type_ == DATA_FRAME_TYPE && header.stream_id == stream_id);
+    data.flat_map(|(_, payload)| payload.clone()).collect()
+}
+
+// Everything a connection driven in memory produced so far, as frames
+fn output_frames(connection: &mut Connection) -> Vec<Received> {
+    let mut output = Vec::new();
+    loop {
+        let more = connection.take_output();
+        if more.is_empty() {
+            break;
+        }
+        output.extend(more);
+    }
+
+    let mut frames = Vec::new();
+    let mut rest = &output[..];
+    while !rest.is_empty() {
+        let header = FrameHeader::from_bytes(rest[..9].try_into().unwrap());
+        let end = 9 + header.length as usize;
+        frames.push((header, rest[9..end].to_vec()));
+        rest = &rest[end..];
+    }
+    frames
+}
+
+#[test]
+fn only_rfc9113_opts_out_of_rfc7540_priorities() {
+    let limits = [0, 3, 0, 0, 0, 100, 0, 5, 0, 0, 0x40, 0];
+    let no_rfc7540_priorities = [0, 9, 0, 0, 0, 1];
+    let cases = [
+        (SpecLevel::Rfc7540, limits.to_vec()),
+        (SpecLevel::Rfc9113, [&limits[..], &no_rfc7540_priorities].concat()),
+    ];
+    for (spec_level, payload) in cases {
+        let mut client = Client::with_config(config(spec_level));
+        let (header, settings) = client.next_frame().unwrap();
+        assert_eq!((header.type_, header.flags), (SETTINGS_FRAME_TYPE, 0));
+        assert_eq!(settings, payload);
+        client.finish();
+    }
+}
+
+#[test]
+fn pings_are_acknowledged_with_their_data() {
+    let mut client = Client::new();
+    client.send(&frame(PING_FRAME_TYPE, 0, 0, b"12345678"));
+    let (header, data) = client.frames_until(PING_FRAME_TYPE).pop().unwrap();
+    assert_eq!((header.flags, header.stream_id, data.as_slice()), (ACK, 0, &b"12345678"[..]));
+
+    // An ACK is not acknowledged in turn
+    client.send(&frame(PING_FRAME_TYPE, ACK, 0, b"12345678"));
+    client.send(&frame(PING_FRAME_TYPE, 0, 0, b"1234567"));
+    let frames = client.finish();
+    assert!(!frames.iter().any(|(header, _)| header.type_ == PING_FRAME_TYPE));
+    assert_eq!(goaway(&frames), Some((0, FRAME_SIZE_ERROR)));
+
+    let mut client = Client::new();
+    client.send(&frame(PING_FRAME_TYPE, 0, 1, b"12345678"));
+    assert_eq!(goaway(&client.finish()), Some((0, PROTOCOL_ERROR)));
+}
+
+#[test]
+fn idle_connections_are_probed() {
+    let config = ServerConfig { keepalive_interval: Duration::from_millis(50), ..config(SpecLevel::Rfc9113) };
+    let mut client = Client::with_config(config);
+    let (header, data) = client.frames_until(PING_FRAME_TYPE).pop().unwrap();
+    assert_eq!((header.flags, data), (0, KEEPALIVE_PING_DATA.to_vec()));
+
+    // Once acknowledged, the connection carries on
+    client.send(&frame(PING_FRAME_TYPE, ACK, 0, &KEEPALIVE_PING_DATA));
+    client.request(1, "GET", "/", &[], true);
+    client.response(1);
+    assert_eq!(client.status(1), Some(&b"200"[..]));
+    client.finish();
+}
+
+#[test]
+fn connection_errors_are_reported_in_goaway() {
+    let cases = [
+        (frame(GOAWAY_FRAME_TYPE, 0, 0, &[0; 7]), FRAME_SIZE_ERROR),
+        (frame(GOAWAY_FRAME_TYPE, 0, 1, &[0; 8]), PROTOCOL_ERROR),
+        (frame(HEADERS_FRAME_TYPE, END_HEADERS | END_STREAM, 0, &request_block("GET", "/", &[])), PROTOCOL_ERROR),
+        (frame(HEADERS_FRAME_TYPE, END_HEADERS | END_STREAM, 1, &[0xff; 4]), COMPRESSION_ERROR),
+        (frame(RST_STREAM_FRAME_TYPE, 0, 0, &CANCEL.to_be_bytes()), PROTOCOL_ERROR),
+        (frame(RST_STREAM_FRAME_TYPE, 0, 1, &CANCEL.to_be_bytes()), PROTOCOL_ERROR),
+        // Frame headers are checked before any payload is read
+        (frame(DATA_FRAME_TYPE, 0, 1, &[0; 16385]), FRAME_SIZE_ERROR),
+        (frame(SETTINGS_FRAME_TYPE, 0, 0, &[0; 5]), FRAME_SIZE_ERROR),
+        (frame(SETTINGS_FRAME_TYPE, ACK, 0, &[0; 6]), FRAME_SIZE_ERROR),
+        (frame(SETTINGS_FRAME_TYPE, 0, 1, &[]), PROTOCOL_ERROR),
+        (frame(PRIORITY_FRAME_TYPE, 0, 1, &[0; 4]), FRAME_SIZE_ERROR),
+        (frame(PRIORITY_FRAME_TYPE, 0, 0, &[0; 5]), PROTOCOL_ERROR),
+        (frame(WINDOW_UPDATE_FRAME_TYPE, 0, 0, &[0; 3]), FRAME_SIZE_ERROR),
+        (frame(HEADERS_FRAME_TYPE, END_HEADERS | PADDED_FLAG, 1, &[]), FRAME_SIZE_ERROR),
+        (frame(HEADERS_FRAME_TYPE, END_HEADERS | PRIORITY_FLAG, 1, &[0; 4]), FRAME_SIZE_ERROR),
+    ];
+    for (bytes, error_code) in cases {
+        let mut client = Client::new();
+        client.send(&bytes);
+        assert_eq!(goaway(&client.finish()).map(|(_, code)| code), Some(error_code));
+    }
+}
+
+#[test]
+fn push_promise_from_a_client_closes_the_connection() {
+    let mut client = Client::new();
+    client.send(&frame(PUSH_PROMISE_FRAME_TYPE, END_HEADERS, 1, &[0, 0, 0, 2]));
+    let frames = client.finish();
+    assert_eq!(goaway(&frames), Some((0, PROTOCOL_ERROR)));
+    let (_, payload) = frames.iter().find(|(header, _)| header.type_ == GOAWAY_FRAME_TYPE).unwrap();
+    assert_eq!(&payload[8..], b"PUSH_PROMISE sent by a client");
+}
+
+#[test]
+fn shutdown_lets_open_streams_finish() {
+    let _exclusive = CONNECTIONS.write().unwrap_or_else(|e| e.into_inner());
+    let mut client = Client::serve(config(SpecLevel::Rfc9113), None);
+    client.start_http2(&[]);
+    client.request(1, "POST", "/echo", &[("content-length", "4")], false);
+    client.send(&frame(PING_FRAME_TYPE, 0, 0, b"stream 1"));
+    client.frames_until(PING_FRAME_TYPE);
+
+    // The GOAWAY comes before the open stream's response, and keeps the
+    // stream the server will still finish
+    SHUTDOWN.store(true, Ordering::SeqCst);
+    let frames = client.frames_until(GOAWAY_FRAME_TYPE);
+    assert_eq!(goaway(&frames), Some((1, NO_ERROR)));
+    assert_eq!(responses(&frames), 0);
+
+    // Later streams are refused, and the connection closes once the open
+    // one is answered
+    client.request(3, "GET", "/", &[], true);
+    assert_eq!(resets(&client.frames_until(RST_STREAM_FRAME_TYPE)), [(3, REFUSED_STREAM)]);
+    client.data(1, b"body", true);
+    assert_eq!(body(&client.response(1), 1), b"body");
+    assert!(client.next_frame().is_none());
+    client.finish();
+    SHUTDOWN.store(false, Ordering::SeqCst);
+}
+
+#[test]
+fn oversized_bodies_are_refused_before_they_are_read() {
+    let mut client = Client::new();
+    client.request(1, "POST", "/", &[("content-length", "2000000")], false);
+
+    // The answer and the reset come while none of the body was sent
+    let frames = client.frames_until(RST_STREAM_FRAME_TYPE);
+    assert_eq!(client.status(1), Some(&b"413"[..]));
+    assert_eq!(resets(&frames), [(1, NO_ERROR)]);
+
+    // What is still in flight of the body is discarded, and the
+    // connection goes on
+    client.data(1, &[b'x'; 16384], true);
+    client.request(3, "POST", "/", &[("content-length", "2000000")], false);
+    assert_eq!(resets(&client.frames_until(RST_STREAM_FRAME_TYPE)), [(3, NO_ERROR)]);
+    assert!(resets(&client.finish()).is_empty());
+}
+
+#[test]
+fn uploads_are_cut_short_by_an_early_response() {
+    let mut client = Client::with_config(ServerConfig { max_body_size: 32768, ..config(SpecLevel::Rfc9113) });
+    client.request(1, "POST", "/", &[], false);
+    // Past the server's SETTINGS and its connection WINDOW_UPDATE
+    client.frames_until(WINDOW_UPDATE_FRAME_TYPE);
+
+    // A body within the limit is read, and credited back to the
+    // connection and the stream
+    for _ in 0..2 {
+        client.data(1, &[b'x'; 16384], false);
+        let mut frames = client.frames_until(WINDOW_UPDATE_FRAME_TYPE);
+        frames.extend(client.frames_until(WINDOW_UPDATE_FRAME_TYPE));
+        assert_eq!(window_updates(&frames), [(0, 16384), (1, 16384)]);
+        assert_eq!(responses(&frames), 0);
+    }
+
+    // Going over it is answered at once, and the upload stopped
+    client.data(1, &[b'x'; 16384], false);
+    let frames = client.frames_until(RST_STREAM_FRAME_TYPE);
+    assert_eq!(client.status(1), Some(&b"413"[..]));
+    assert_eq!(resets(&frames), [(1, NO_ERROR)]);
+
+    // The client keeps uploading anyway; all of it is read past
+    for _ in 0..640 {
+        client.data(1, &[b'x'; 16384], false);
+    }
+    client.data(1, &[], true);
+    client.request(3, "GET", "/", &[], true);
+    let frames = client.finish();
+    assert_eq!(responses(&frames), 1);
+    assert!(resets(&frames).is_empty());
+}
+
+#[test]
+fn request_bodies_arrive_in_data_frames() {
+    let mut client = Client::new();
+    client.request(1, "POST", "/echo", &[("content-length", "4")], false);
+    client.data(1, b"bo", false);
+    client.data(1, b"dy", true);
+    let frames = client.response(1);
+    assert_eq!(body(&frames, 1), b"body");
+
+    // A body that disagrees with content-length makes the request malformed
+    client.request(3, "POST", "/echo", &[("content-length", "5")], false);
+    client.data(3, b"body", true);
+    client.response(3);
+    client.request(5, "POST", "/echo", &[("content-length", "3")], false);
+    client.data(5, b"body", true);
+    client.response(5);
+    assert_eq!((client.status(3), client.status(5)), (Some(&b"400"[..]), Some(&b"400"[..])));
+    assert_eq!(resets(&client.finish()), []);
+}
+
+#[test]
+fn malformed_requests_get_what_the_policy_says() {
+    let missing_path = Encoder::new().encode(&vec![(b":method".to_vec(), b"GET".to_vec())]);
+    let blocks = [
+        request_block("GET", "/", &[("X-Name", "value")]),
+        missing_path,
+        request_block("POST", "/", &[("content-length", "5x")]),
+    ];
+    for block in &blocks {
+        let respond = ServerConfig { malformed_policy: MalformedPolicy::Respond, ..config(SpecLevel::Rfc9113) };
+        let mut client = Client::with_config(respond);
+        client.send(&frame(HEADERS_FRAME_TYPE, END_HEADERS | END_STREAM, 1, block));
+        client.response(1);
+        assert_eq!(client.status(1), Some(&b"400"[..]));
+        assert_eq!(resets(&client.finish()), []);
+
+        let reset = ServerConfig { malformed_policy: MalformedPolicy::Reset, ..config(SpecLevel::Rfc9113) };
+        let mut client = Client::with_config(reset);
+        client.send(&frame(HEADERS_FRAME_TYPE, END_HEADERS | END_STREAM, 1, block));
+        let frames = client.finish();
+        assert_eq!(responses(&frames), 0);
+        assert_eq!(resets(&frames), [(1, PROTOCOL_ERROR)]);
+    }
+}
+
+#[test]
+fn small_bodies_are_drained_within_a_limit() {
+    let mut client = Client::with_config(ServerConfig { max_body_size: 32768, ..config(SpecLevel::Rfc9113) });
+    client.request(1, "POST", "/", &[("content-length", "65535")], false);
+    assert_eq!(responses(&client.frames_until(DATA_FRAME_TYPE)), 1);
+    assert_eq!(client.status(1), Some(&b"413"[..]));
+
+    // Drained DATA is credited back to the connection window
+    for length in [16384, 16384, 16384, 16383] {
+        client.data(1, &vec![b'x'; length], false);
+        let frames = client.frames_until(WINDOW_UPDATE_FRAME_TYPE);
+        assert_eq!(window_updates(&frames), [(0, length as u32)]);
+        assert!(resets(&frames).is_empty());
+    }
+
+    // A client that keeps uploading is reset once it exceeds the declared
+    // length, not after the whole upload
+    client.data(1, &[b'x'; 16384], false);
+    assert_eq!(resets(&client.frames_until(RST_STREAM_FRAME_TYPE)), [(1, NO_ERROR)]);
+    for _ in 0..640 {
+        client.data(1, &[b'x'; 16384], false);
+    }
+    client.data(1, &[], true);
+    assert!(resets(&client.finish()).is_empty());
+}
+
+#[test]
+fn each_settings_frame_is_acknowledged_once() {
+    let mut client = Client::new();
+    client.send(&settings_frame(&[(SETTINGS_INITIAL_WINDOW_SIZE, 100)]));
+    client.send(&settings_frame(&[(SETTINGS_INITIAL_WINDOW_SIZE, 10)]));
+    client.request(1, "POST", "/echo", &[], false);
+    client.data(1, &[b'x'; 1000], true);
+
+    // One ACK for the SETTINGS that opened the connection and one for each
+    // of the two after it, whose last value applies
+    let frames = client.frames_until(DATA_FRAME_TYPE);
+    let acks = frames.iter().filter(|(header, _)| header.type_ == SETTINGS_FRAME_TYPE && header.flags == ACK);
+    assert_eq!(acks.count(), 3);
+    assert_eq!(body(&frames, 1).len(), 10);
+    client.finish();
+}
+
+#[test]
+fn large_bodies_go_out_as_the_windows_allow() {
+    let mut client = Client::new();
+
+    let body: Vec<u8> = (0..1_000_000u32).map(|i| i as u8).collect();
+    client.request(1, "POST", "/echo", &[], false);
+    for chunk in body.chunks(16384) {
+        client.data(1, chunk, false);
+    }
+    client.data(1, &[], true);
+
+    // The client's windows start at 65535 bytes, and are granted again
+    // each time the response used them up
+    let mut received = Vec::new();
+    let mut window = DEFAULT_WINDOW_SIZE as usize;
+    while received.len() < body.len() {
+        let (header, data) = client.next_frame().expect("connection closed");
+        if header.type_ != DATA_FRAME_TYPE {
+            continue;
+        }
+        assert!(data.len() <= 16384 && data.len() <= window);
+        window -= data.len();
+        received.extend(data);
+        assert_eq!(header.flags & END_STREAM == END_STREAM, received.len() == body.len());
+
+        if window == 0 {
+            client.send(&window_update(0, DEFAULT_WINDOW_SIZE));
+            client.send(&window_update(1, DEFAULT_WINDOW_SIZE));
+            window = DEFAULT_WINDOW_SIZE as usize;
+        }
+    }
+    assert!(received == body);
+    client.finish();
+}
+
+#[test]
+fn cancelled_responses_stop_being_sent() {
+    let mut client = Client::new();
+    client.request(1, "POST", "/echo", &[], false);
+    for _ in 0..10 {
+        client.data(1, &[b'x'; 16384], false);
+    }
+    client.data(1, &[], true);
+
+    // The response fills the client's windows
+    let mut sent = 0;
+    while sent < DEFAULT_WINDOW_SIZE as usize {
+        let (header, data) = client.next_frame().expect("connection closed");
+        if header.type_ == DATA_FRAME_TYPE {
+            sent += data.len();
+        }
+    }
+
+    // Nothing more of it comes once the client reset the stream, while
+    // other streams carry on
+    client.send(&frame(RST_STREAM_FRAME_TYPE, 0, 1, &CANCEL.to_be_bytes()));
+    client.send(&window_update(0, 1_000_000));
+    client.send(&window_update(1, 1_000_000));
+    client.request(3, "GET", "/", &[], true);
+    client.send(&frame(RST_STREAM_FRAME_TYPE, 0, 3, &[0; 3]));
+    let frames = client.finish();
+    assert!(body(&frames, 1).is_empty());
+    assert_eq!(responses(&frames), 1);
+    assert_eq!(goaway(&frames), Some((3, FRAME_SIZE_ERROR)));
+}
+
+#[test]
+fn negative_windows_hold_back_data() {
+    let mut client = Client::open(config(SpecLevel::Rfc9113), &[(SETTINGS_INITIAL_WINDOW_SIZE, 10)]);
+    let body = [b'x'; 100];
+    client.request(1, "POST", "/echo", &[], false);
+    client.data(1, &body, true);
+    assert_eq!(data_lengths(&client.frames_until(DATA_FRAME_TYPE)), [10]);
+
+    // Taking the 10 bytes back leaves the stream window at -10, and 5 more
+    // only bring it to -5
+    client.send(&settings_frame(&[(SETTINGS_INITIAL_WINDOW_SIZE, 0)]));
+    client.send(&window_update(1, 5));
+    client.send(&frame(PING_FRAME_TYPE, 0, 0, b"barrier!"));
+    assert_eq!(data_lengths(&client.frames_until(PING_FRAME_TYPE)), []);
+
+    client.send(&window_update(1, 20));
+    assert_eq!(data_lengths(&client.frames_until(DATA_FRAME_TYPE)), [15]);
+    client.finish();
+}
+
+#[test]
+fn windows_may_not_overflow() {
+    let mut client = Client::new();
+    client.request(1, "POST", "/echo", &[], false);
+    client.send(&window_update(1, 0x7fff_ffff));
+    assert_eq!(resets(&client.frames_until(RST_STREAM_FRAME_TYPE)), [(1, FLOW_CONTROL_ERROR)]);
+
+    client.send(&window_update(0, 0x7fff_ffff));
+    assert_eq!(goaway(&client.finish()), Some((1, FLOW_CONTROL_ERROR)));
+}
+
+#[test]
+fn responses_follow_the_clients_header_table_size() {
+    let mut client = Client::open(config(SpecLevel::Rfc9113), &[(SETTINGS_HEADER_TABLE_SIZE, 0)]);
+    client.request(1, "GET", "/", &[], true);
+    client.request(3, "GET", "/", &[], true);
+    let mut frames = client.response(1);
+    frames.extend(client.response(3));
+
+    // Each block empties the client's table first, and only refers to the
+    // static table
+    let blocks: Vec<_> = frames.iter().filter(|(header, _)| header.type_ == HEADERS_FRAME_TYPE).collect();
+    assert_eq!(blocks.len(), 2);
+    assert!(blocks.iter().all(|(_, block)| block[0] == 0x20));
+
+    client.send(&settings_frame(&[(SETTINGS_HEADER_TABLE_SIZE, DEFAULT_HEADER_TABLE_SIZE)]));
+    client.request(5, "GET", "/", &[], true);
+    client.response(5);
+
+    // The client decodes all of them with the one table it keeps
+    assert_eq!(client.header_lists.len(), 3);
+    assert!(client.header_lists.iter().all(|(_, fields)| *fields == client.header_lists[0].1));
+    assert_eq!(client.status(5), Some(&b"200"[..]));
+    client.finish();
+}
+
+#[test]
+fn similar_requests_take_fewer_bytes_for_the_same_fields() {
+    let mut connection = Connection::new(config(SpecLevel::Rfc9113));
+    let mut start = CONNECTION_PREFACE.to_vec();
+    start.extend(settings_frame(&[]));
+    connection.receive(&start);
+
+    // The client's encoder refers back to what the first request added
+    let mut encoder = Encoder::new();
+    let fields = [(":method", "GET"), (":scheme", "http"), (":path", "/"), ("x-client", "test-client/1.0")];
+    let fields = fields.iter().map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()));
+    let fields: HeaderList = fields.collect();
+    let mut sizes = Vec::new();
+    for stream_id in [1, 3] {
+        let block = encoder.encode(&fields);
+        connection.receive(&frame(HEADERS_FRAME_TYPE, END_HEADERS | END_STREAM, stream_id, &block));
+        let received = &connection.received_headers;
+        sizes.push((received.blocks, received.compressed, received.uncompressed));
+    }
+    let [(1, first, first_fields), (2, both, both_fields)] = sizes[..] else {
+        panic!("unexpected block counts: {:?}", sizes);
+    };
+    assert!(both - first < first, "{:?}", sizes);
+    assert_eq!(both_fields - first_fields, first_fields);
+}
+
+#[test]
+fn requests_may_only_shrink_our_header_table() {
+    let mut client = Client::new();
+    let block = [&[0x20][..], &request_block("GET", "/", &[])].concat();
+    client.send(&frame(HEADERS_FRAME_TYPE, END_HEADERS | END_STREAM, 1, &block));
+    client.response(1);
+
+    // 4097 bytes, one more than we allow
+    let block = [&[0x3f, 0xe2, 0x1f][..], &request_block("GET", "/", &[])].concat();
+    client.send(&frame(HEADERS_FRAME_TYPE, END_HEADERS | END_STREAM, 3, &block));
+    let frames = client.finish();
+    assert_eq!(responses(&frames), 0);
+    assert_eq!(goaway(&frames), Some((3, COMPRESSION_ERROR)));
+}
+
+#[test]
+fn empty_header_blocks_are_only_valid_as_trailers() {
+    let mut client = Client::new();
+
+    // Without any fields, a request lacks its pseudo-headers
+    client.send(&frame(HEADERS_FRAME_TYPE, END_HEADERS | END_STREAM, 1, &[]));
+    client.response(1);
+    assert_eq!(client.status(1), Some(&b"400"[..]));
+
+    // The trailers of a request may be empty, even once it was answered
+    client.request(3, "POST", "/", &[("content-length", "4")], false);
+    client.data(3, b"body", false);
+    client.send(&frame(HEADERS_FRAME_TYPE, END_HEADERS | END_STREAM, 3, &[]));
+    client.send(&frame(HEADERS_FRAME_TYPE, END_HEADERS | END_STREAM, 5, &[]));
+
+    // Empty CONTINUATION frames neither add to a block nor end it
+    client.send(&frame(HEADERS_FRAME_TYPE, END_STREAM, 7, &request_block("GET", "/", &[])));
+    client.send(&frame(CONTINUATION_FRAME_TYPE, 0, 7, &[]));
+    client.send(&frame(CONTINUATION_FRAME_TYPE, END_HEADERS, 7, &[]));
+    for stream_id in [3, 5, 7] {
+        client.response(stream_id);
+    }
+    let statuses = [3, 5, 7].map(|stream_id| client.status(stream_id).unwrap().to_vec());
+    assert_eq!(statuses, [b"200", b"400", b"200"]);
+    assert_eq!(resets(&client.finish()), []);
+}
+
+#[test]
+fn header_blocks_continue_in_continuation_frames() {
+    let mut client = Client::new();
+    let large = "a".repeat(20_000);
+    let block = request_block("GET", "/echo", &[("x-large", &large)]);
+    let (first, rest) = block.split_at(8000);
+    let (second, third) = rest.split_at(8000);
+
+    client.send(&frame(HEADERS_FRAME_TYPE, END_STREAM, 1, first));
+    client.send(&frame(CONTINUATION_FRAME_TYPE, 0, 1, second));
+    client.send(&frame(CONTINUATION_FRAME_TYPE, END_HEADERS, 1, third));
+    let frames = client.response(1);
+    assert!(String::from_utf8(body(&frames, 1)).unwrap().ends_with(&format!("x-large: {}\n", large)));
+    assert!(resets(&frames).is_empty());
+
+    // No other frame may come between those of a block
+    client.send(&frame(HEADERS_FRAME_TYPE, END_STREAM, 3, first));
+    client.send(&frame(CONTINUATION_FRAME_TYPE, 0, 3, second));
+    client.send(&frame(WINDOW_UPDATE_FRAME_TYPE, 0, 0, &1u32.to_be_bytes()));
+    assert_eq!(goaway(&client.finish()), Some((3, PROTOCOL_ERROR)));
+}
+
+#[cfg(feature = "static-files")]
+#[test]
+fn files_are_served_from_the_root() {
+    let parent = std::env::temp_dir().join(format!("files-{}", std::process::id()));
+    let root = parent.join("root");
+    let _ = fs::remove_dir_all(&parent);
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("index.html"), "<p>home</p>").unwrap();
+    fs::write(root.join("site.css"), "p {}").unwrap();
+    fs::write(parent.join("secret.txt"), "secret").unwrap();
+    std::os::unix::fs::symlink(parent.join("secret.txt"), root.join("secret.txt")).unwrap();
+
+    let config = ServerConfig { root: Some(fs::canonicalize(&root).unwrap()), ..config(SpecLevel::Rfc9113) };
+    let mut client = Client::with_config(config);
+    client.request(1, "GET", "/", &[], true);
+    assert_eq!(body(&client.response(1), 1), b"<p>home</p>");
+    assert_eq!(client.field(1, "content-type"), Some(&b"text/html; charset=utf-8"[..]));
+    client.request(3, "GET", "/site.css", &[], true);
+    assert_eq!(body(&client.response(3), 3), b"p {}");
+    assert_eq!(client.field(3, "content-type"), Some(&b"text/css"[..]));
+
+    // A symbolic link out of the root is no different from a missing file
+    for (stream_id, path) in [(5, "/secret.txt"), (7, "/missing.txt"), (9, "/../secret.txt")] {
+        client.request(stream_id, "GET", path, &[], true);
+        client.response(stream_id);
+        assert_eq!(client.status(stream_id), Some(&b"404"[..]), "{}", path);
+    }
+
+    client.request(11, "POST", "/site.css", &[], true);
+    client.response(11);
+    assert_eq!(client.status(11), Some(&b"405"[..]));
+    assert_eq!(client.field(11, "allow"), Some(&b"GET"[..]));
+    client.finish();
+    fs::remove_dir_all(parent).unwrap();
+}
+
+#[cfg(not(feature = "static-files"))]
+#[test]
+fn roots_are_ignored_without_static_files() {
+    let root = std::env::temp_dir().join(format!("ignored-root-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("index.html"), "<p>home</p>").unwrap();
+
+    let mut client = Client::with_config(ServerConfig { root: Some(root.clone()), ..config(SpecLevel::Rfc9113) });
+    client.request(1, "GET", "/", &[], true);
+    assert_eq!(body(&client.response(1), 1), b"Hello, world!");
+    client.request(3, "GET", "/index.html", &[], true);
+    client.response(3);
+    assert_eq!(client.status(3), Some(&b"404"[..]));
+    client.finish();
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn plain_http1_requests_are_answered() {
+    let mut client = Client::connect(config(SpecLevel::Rfc9113));
+    client.send(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello");
+
+    // The connection closes after the response
+    let output = String::from_utf8(client.bytes(usize::MAX)).unwrap();
+    assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{}", output);
+    assert!(output.ends_with("connection: close\r\n\r\nhello"), "{}", output);
+    client.finish();
+}
+
+#[test]
+fn handler_time_is_reported_only_when_asked_for() {
+    for server_timing in [false, true] {
+        let mut client = Client::with_config(ServerConfig { server_timing, ..config(SpecLevel::Rfc9113) });
+        client.request(1, "GET", "/", &[], true);
+        client.response(1);
+        let timing = client.field(1, "server-timing");
+        assert_eq!(timing.is_some(), server_timing);
+        assert!(timing.is_none_or(|timing| timing.starts_with(b"handler;dur=")), "{:?}", timing);
+        client.finish();
+
+        let mut client = Client::connect(ServerConfig { server_timing, ..config(SpecLevel::Rfc9113) });
+        client.send(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let output = String::from_utf8(client.bytes(usize::MAX)).unwrap();
+        assert_eq!(output.contains("\r\nserver-timing: handler;dur="), server_timing, "{}", output);
+        client.finish();
+    }
+}
+
+#[test]
+fn bad_http1_requests_get_an_error_status() {
+    let large_head = format!("GET / HTTP/1.1\r\nx-large: {}\r\n\r\n", "a".repeat(MAX_HTTP1_HEAD_SIZE));
+    let cases = [
+        ("GET /\r\n\r\n".to_string(), "400"),
+        ("POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_string(), "501"),
+        ("POST /echo HTTP/1.1\r\nContent-Length: 2000000\r\n\r\n".to_string(), "413"),
+        (large_head, "431"),
+    ];
+    for (request, status) in cases {
+        let mut client = Client::connect(config(SpecLevel::Rfc9113));
+        client.send(request.as_bytes());
+        let output = String::from_utf8(client.bytes(usize::MAX)).unwrap();
+        assert!(output.starts_with(&format!("HTTP/1.1 {} ", status)), "{}", output);
+        client.finish();
+    }
+}
+
+#[test]
+fn h2c_upgrades_answer_the_request_on_stream_1() {
+    // HTTP2-Settings carries SETTINGS_INITIAL_WINDOW_SIZE=10
+    let mut client = Client::connect(config(SpecLevel::Rfc9113));
+    client.send(
+        b"GET /echo HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\n\
+          HTTP2-Settings: AAQAAAAK\r\n\r\n",
+    );
+    let head = b"HTTP/1.1 101 Switching Protocols\r\nconnection: Upgrade\r\nupgrade: h2c\r\n\r\n";
+    assert_eq!(client.bytes(head.len()), head);
+
+    // The response comes once the client sent its preface, within the
+    // window the upgrade request allowed
+    client.start_http2(&[]);
+    let frames = client.frames_until(DATA_FRAME_TYPE);
+    assert_eq!(client.status(1), Some(&b"200"[..]));
+    assert_eq!(body(&frames, 1).len(), 10);
+
+    client.send(&settings_frame(&[(SETTINGS_INITIAL_WINDOW_SIZE, DEFAULT_WINDOW_SIZE)]));
+    let mut echoed = body(&frames, 1);
+    echoed.extend(body(&client.response(1), 1));
+    let echoed = String::from_utf8(echoed).unwrap();
+    assert!(echoed.starts_with(":method: GET\n:path: /echo\n:scheme: http\n:authority: localhost\n"), "{}", echoed);
+    assert!(!echoed.contains("upgrade") && !echoed.contains("http2-settings"), "{}", echoed);
+
+    // Stream 1 is taken; the client goes on with stream 3
+    client.request(3, "GET", "/", &[], true);
+    client.response(3);
+    assert_eq!(client.status(3), Some(&b"200"[..]));
+    client.finish();
+}
+
+#[test]
+fn connections_record_how_the_client_talks() {
+    let upgrade = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\n\
+                    HTTP2-Settings: AAQAAAAK\r\n\r\n";
+    let cases = [
+        (CONNECTION_PREFACE.to_vec(), Protocol::PriorKnowledge),
+        (upgrade.to_vec(), Protocol::Upgrade),
+        (b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(), Protocol::Http1),
+        (b"GET /\r\n\r\n".to_vec(), Protocol::Http1),
+        (vec![b'a'; MAX_HTTP1_HEAD_SIZE], Protocol::Http1),
+    ];
+    for (bytes, protocol) in cases {
+        let mut connection = Connection::new(config(SpecLevel::Rfc9113));
+        connection.receive(&bytes);
+        assert_eq!(connection.protocol, Some(protocol));
+    }
+}
+
+#[test]
+fn settings_acknowledgments_of_nothing_are_ignored() {
+    let mut client = Client::new();
+    client.send(&frame(SETTINGS_FRAME_TYPE, ACK, 0, &[]));
+    client.send(&frame(SETTINGS_FRAME_TYPE, ACK, 0, &[]));
+    client.request(1, "GET", "/", &[], true);
+    client.response(1);
+    assert_eq!(client.status(1), Some(&b"200"[..]));
+    assert_eq!(goaway(&client.finish()), None);
+}
+
+#[test]
+fn our_settings_apply_once_acknowledged() {
+    let mut connection = Connection::new(ServerConfig { max_concurrent_streams: 7, ..config(SpecLevel::Rfc9113) });
+    let mut start = CONNECTION_PREFACE.to_vec();
+    start.extend(settings_frame(&[]));
+    connection.receive(&start);
+    let frames = output_frames(&mut connection);
+    let ours = frames.iter().find(|(header, _)| header.type_ == SETTINGS_FRAME_TYPE && header.flags & ACK == 0);
+    let (_, advertised) = ours.unwrap();
+    assert!(advertised.chunks(6).any(|setting| setting == [0, 3, 0, 0, 0, 7]));
+    assert_ne!(connection.local_settings.max_concurrent_streams, 7);
+
+    connection.receive(&frame(SETTINGS_FRAME_TYPE, ACK, 0, &[]));
+    assert_eq!(connection.local_settings.max_concurrent_streams, 7);
+    assert!(connection.pending_settings.is_none());
+}
+
+#[test]
+fn warnings_are_counted_per_connection() {
+    let warning = "SETTINGS acknowledgment without pending settings";
+    let mut connection = Connection::new(config(SpecLevel::Rfc9113));
+    let mut bytes = CONNECTION_PREFACE.to_vec();
+    bytes.extend(settings_frame(&[]));
+    // Our own SETTINGS is still waiting for its acknowledgment
+    bytes.extend(frame(SETTINGS_FRAME_TYPE, ACK, 0, &[]));
+    for _ in 0..10_000 {
+        bytes.extend(frame(SETTINGS_FRAME_TYPE, ACK, 0, &[]));
+    }
+    connection.receive(&bytes);
+    assert!(!connection.is_closed());
+    assert_eq!(connection.warnings.count(warning), 10_000);
+
+    // Another connection starts counting afresh
+    assert_eq!(Connection::new(config(SpecLevel::Rfc9113)).warnings.count(warning), 0);
+}
+
+// Body and header block bytes among the frames in `bytes`, which may
+// start with the connection preface
+fn content(mut bytes: &[u8]) -> u64 {
+    bytes = bytes.strip_prefix(&CONNECTION_PREFACE[..]).unwrap_or(bytes);
+    let mut content = 0;
+    while !bytes.is_empty() {
+        let header = FrameHeader::from_bytes(bytes[..9].try_into().unwrap());
+        let end = 9 + header.length as usize;
+        content += match Frame::parse(header, &bytes[9..end]).unwrap() {
+            Frame::Data { data, .. } => data.len(),
+            Frame::Headers { fragment, .. } | Frame::Continuation { fragment, .. } => fragment.len(),
+            _ => 0,
+        } as u64;
+        bytes = &bytes[end..];
+    }
+    content
+}
+
+#[test]
+fn traffic_splits_into_content_and_overhead() {
+    let mut input = CONNECTION_PREFACE.to_vec();
+    input.extend(settings_frame(&[(SETTINGS_INITIAL_WINDOW_SIZE, 1 << 20)]));
+    input.extend(frame(SETTINGS_FRAME_TYPE, ACK, 0, &[]));
+    let fragment = request_block("POST", "/echo", &[]);
+    let (head, tail) = fragment.split_at(fragment.len() / 2);
+    input.extend(
+        Frame::Headers {
+            stream_id: 1,
+            end_stream: false,
+            end_headers: false,
+            padding: Some(4),
+            priority: None,
+            fragment: head.to_vec(),
+        }
+        .serialize(),
+    );
+    input.extend(frame(CONTINUATION_FRAME_TYPE, END_HEADERS, 1, tail));
+    for (end_stream, padding, length) in [(false, Some(10), 1000), (true, None, 234)] {
+        input.extend(Frame::Data { stream_id: 1, end_stream, padding, data: vec![b'x'; length] }.serialize());
+    }
+    input.extend(frame(PING_FRAME_TYPE, 0, 0, &[1; 8]));
+
+    // In pieces, as they may come off a socket
+    let mut connection = Connection::new(config(SpecLevel::Rfc9113));
+    for piece in input.chunks(7) {
+        connection.receive(piece);
+    }
+    let output = connection.take_output();
+
+    assert_eq!(connection.bytes_received, input.len() as u64);
+    assert_eq!(connection.body_received, 1234);
+    assert_eq!(connection.received_headers.compressed, fragment.len());
+    assert_eq!(connection.body_received + connection.received_headers.compressed as u64, content(&input));
+
+    assert_eq!(connection.bytes_sent, output.len() as u64);
+    assert_eq!(connection.flow.body_sent, 1234);
+    assert_eq!(connection.flow.body_sent + connection.flow.sent_headers.compressed as u64, content(&output));
+
+    let summary = traffic_summary(connection.bytes_received, connection.body_received, fragment.len());
+    let (total, headers) = (input.len(), fragment.len());
+    let overhead = total - 1234 - headers;
+    let expected = format!("{} bytes: 1234 of bodies, {} of header blocks, {} of overhead", total, headers, overhead);
+    assert!(summary.starts_with(&expected), "{}", summary);
+}
+
+#[test]
+fn connections_are_torn_down_once() {
+    // GOAWAY frames among what the server produced
+    let goaways = |output: &[u8]| {
+        let mut rest = output;
+        let mut count = 0;
+        while !rest.is_empty() {
+            let header = FrameHeader::from_bytes(rest[..9].try_into().unwrap());
+            count += usize::from(header.type_ == GOAWAY_FRAME_TYPE);
+            rest = &rest[9 + header.length as usize..];
+        }
+        count
+    };
+    let eof = |connection: &mut Connection| connection.receive_eof();
+    let io_error = |connection: &mut Connection| connection.fail(io::Error::other("write failed"));
+    let client_goaway = |connection: &mut Connection| {
+        let goaway = Frame::Goaway { last_stream_id: 0, error_code: NO_ERROR, debug_data: Vec::new() };
+        connection.receive(&goaway.serialize())
+    };
+    // PING belongs to the connection, not to a stream
+    let protocol_error = |connection: &mut Connection| connection.receive(&frame(PING_FRAME_TYPE, 0, 1, &[0; 8]));
+
+    // What ends the connection, and whether that is reported in a GOAWAY
+    type Ending<'a> = &'a dyn Fn(&mut Connection);
+    let endings: [(&str, Ending, bool); 4] = [
+        ("EOF", &eof, false),
+        ("I/O error", &io_error, false),
+        ("GOAWAY", &client_goaway, false),
+        ("protocol error", &protocol_error, true),
+    ];
+    for (name, end, reported) in endings {
+        let mut connection = Connection::new(config(SpecLevel::Rfc9113));
+        let mut start = CONNECTION_PREFACE.to_vec();
+        start.extend(settings_frame(&[]));
+        start.extend(frame(HEADERS_FRAME_TYPE, END_HEADERS, 1, &request_block("POST", "/echo", &[])));
+        start.extend(frame(HEADERS_FRAME_TYPE, END_HEADERS | END_STREAM, 3, &request_block("GET", "/", &[])));
+        connection.receive(&start);
+        connection.take_output();
+
+        end(&mut connection);
+        assert!(connection.is_closed(), "{}", name);
+        assert_eq!(connection.lingers(), reported, "{}", name);
+        assert!(!connection.busy(), "{}", name);
+        assert_eq!(goaways(&connection.take_output()), usize::from(reported), "{}", name);
+
+        // Whatever else happens, the connection stays closed and silent
+        eof(&mut connection);
+        io_error(&mut connection);
+        protocol_error(&mut connection);
+        connection.on_tick();
+        assert!(connection.take_output().is_empty(), "{}", name);
+        assert!(connection.is_closed(), "{}", name);
+    }
+
+    // Dropping an open connection tears it down too
+    let mut connection = Connection::new(config(SpecLevel::Rfc9113));
+    let mut start = CONNECTION_PREFACE.to_vec();
+    start.extend(settings_frame(&[]));
+    start.extend(frame(HEADERS_FRAME_TYPE, END_HEADERS, 1, &request_block("POST", "/echo", &[])));
+    connection.receive(&start);
+    drop(connection);
+}
+
+// A connection driven in memory, past the preface and both SETTINGS
+fn started(config: ServerConfig) -> Connection {
+    let mut connection = Connection::new(config);
+    let mut start = CONNECTION_PREFACE.to_vec();
+    start.extend(settings_frame(&[]));
+    start.extend(frame(SETTINGS_FRAME_TYPE, ACK, 0, &[]));
+    connection.receive(&start);
+    output_frames(&mut connection);
+    connection
+}
+
+#[test]
+fn streams_the_client_resets_are_forgotten() {
+    let mut config = config(SpecLevel::Rfc9113);
+    config.max_concurrent_streams = 1;
+    let mut connection = started(config);
+
+    for stream_id in (1..2000).step_by(2) {
+        let block = request_block("POST", "/echo", &[("content-length", "10")]);
+        connection.receive(&frame(HEADERS_FRAME_TYPE, END_HEADERS, stream_id, &block));
+        connection.receive(&frame(RST_STREAM_FRAME_TYPE, 0, stream_id, &CANCEL.to_be_bytes()));
+        assert!(!connection.busy());
+    }
+    assert!(resets(&output_frames(&mut connection)).is_empty());
+
+    // Not one of them still counts against the limit of one stream
+    connection.receive(&frame(HEADERS_FRAME_TYPE, END_HEADERS | END_STREAM, 2001, &request_block("GET", "/", &[])));
+    assert_eq!(responses(&output_frames(&mut connection)), 1);
+}
+
+#[test]
+fn streams_we_reset_are_remembered_within_a_bound() {
+    let mut connection = started(config(SpecLevel::Rfc9113));
+
+    // A stream depending on itself is reset while the client still sends
+    for stream_id in (1u32..2000).step_by(2) {
+        let mut payload = stream_id.to_be_bytes().to_vec();
+        payload.push(15);
+        payload.extend(request_block("POST", "/echo", &[]));
+        connection.receive(&frame(HEADERS_FRAME_TYPE, END_HEADERS | PRIORITY_FLAG, stream_id, &payload));
+        assert!(!connection.busy());
+        assert_eq!(connection.streams.draining(), 0);
+    }
+    assert_eq!(resets(&output_frames(&mut connection)).len(), 1000);
+
+    // Only the latest resets are remembered: data still in flight on a
+    // recent stream is ignored, on an older one it is a stream error
+    assert!(connection.streams.was_reset(1999));
+    assert!(!connection.streams.was_reset(1));
+    connection.receive(&frame(DATA_FRAME_TYPE, END_STREAM, 1999, b"late"));
+    assert!(resets(&output_frames(&mut connection)).is_empty());
+    connection.receive(&frame(DATA_FRAME_TYPE, END_STREAM, 1, b"late"));
+    assert_eq!(resets(&output_frames(&mut connection)), [(1, STREAM_CLOSED)]);
+    assert!(!connection.is_closed());
+}
+
+#[test]
+fn data_after_end_stream_stops_the_response() {
+    let mut connection = started(config(SpecLevel::Rfc9113));
+
+    // An echoed body larger than the stream window waits for WINDOW_UPDATE
+    connection.receive(&frame(HEADERS_FRAME_TYPE, END_HEADERS, 1, &request_block("POST", "/echo", &[])));
+    for end_stream in [false, false, false, false, true] {
+        connection.receive(&frame(DATA_FRAME_TYPE, if end_stream { END_STREAM } else { 0 }, 1, &[b'x'; 16000]));
+    }
+    let sent: usize = data_lengths(&output_frames(&mut connection)).iter().sum();
+    assert_eq!(sent, DEFAULT_WINDOW_SIZE as usize);
+
+    connection.receive(&frame(DATA_FRAME_TYPE, END_STREAM, 1, b"more"));
+    assert!(resets(&output_frames(&mut connection)).contains(&(1, STREAM_CLOSED)));
+    assert!(!connection.busy());
+
+    connection.receive(&window_update(1, 100_000));
+    assert!(data_lengths(&output_frames(&mut connection)).is_empty());
+    assert!(!connection.is_closed());
+}
+
+#[test]
+fn priority_on_itself_resets_only_streams_in_progress() {
+    let mut connection = started(config(SpecLevel::Rfc9113));
+    let depend_on_itself = |stream_id: u32| {
+        let mut payload = stream_id.to_be_bytes().to_vec();
+        payload.push(15);
+        frame(PRIORITY_FRAME_TYPE, 0, stream_id, &payload)
+    };
+
+    // An idle stream, and one already closed, are left alone
+    connection.receive(&depend_on_itself(5));
+    connection.receive(&frame(HEADERS_FRAME_TYPE, END_HEADERS | END_STREAM, 1, &request_block("GET", "/", &[])));
+    connection.receive(&depend_on_itself(1));
+    assert!(resets(&output_frames(&mut connection)).is_empty());
+
+    connection.receive(&frame(HEADERS_FRAME_TYPE, END_HEADERS, 3, &request_block("POST", "/echo", &[])));
+    connection.receive(&depend_on_itself(3));
+    assert_eq!(resets(&output_frames(&mut connection)), [(3, PROTOCOL_ERROR)]);
+
+    // The idle stream may still be opened
+    connection.receive(&frame(HEADERS_FRAME_TYPE, END_HEADERS | END_STREAM, 5, &request_block("GET", "/", &[])));
+    assert_eq!(responses(&output_frames(&mut connection)), 1);
+    assert!(!connection.is_closed());
+}
+
+#[test]
+fn unknown_frames_are_ignored() {
+    let mut connection = started(config(SpecLevel::Rfc9113));
+    let unknown = |stream_id| frame(0xfa, 0xff, stream_id, b"extension");
+
+    connection.receive(&unknown(0));
+    connection.receive(&unknown(7));
+    assert!(output_frames(&mut connection).is_empty());
+    connection.receive(&frame(HEADERS_FRAME_TYPE, END_HEADERS | END_STREAM, 1, &request_block("GET", "/", &[])));
+    assert_eq!(responses(&output_frames(&mut connection)), 1);
+
+    // Except in the middle of a header block
+    connection.receive(&frame(HEADERS_FRAME_TYPE, END_STREAM, 3, &request_block("GET", "/", &[])));
+    connection.receive(&unknown(3));
+    assert_eq!(goaway(&output_frames(&mut connection)), Some((3, PROTOCOL_ERROR)));
+    assert!(connection.is_closed());
+}
+
+#[test]
+fn rejected_bodies_drain_up_to_their_length() {
+    let mut connection = started(ServerConfig { max_body_size: 100, ..config(SpecLevel::Rfc9113) });
+    let post = |stream_id, length: &str| {
+        let block = request_block("POST", "/echo", &[("content-length", length)]);
+        frame(HEADERS_FRAME_TYPE, END_HEADERS, stream_id, &block)
+    };
+
+    // Drained bytes are returned to the connection window
+    connection.receive(&post(1, "1000"));
+    assert_eq!(responses(&output_frames(&mut connection)), 1);
+    connection.receive(&frame(DATA_FRAME_TYPE, 0, 1, &[b'x'; 600]));
+    connection.receive(&frame(DATA_FRAME_TYPE, END_STREAM, 1, &[b'x'; 400]));
+    let frames = output_frames(&mut connection);
+    assert!(resets(&frames).is_empty());
+    assert_eq!(window_updates(&frames), [(0, 600), (0, 400)]);
+    assert!(!connection.busy());
+
+    // Not beyond the declared length
+    connection.receive(&post(3, "1000"));
+    connection.receive(&frame(DATA_FRAME_TYPE, 0, 3, &[b'x'; 1200]));
+    assert_eq!(resets(&output_frames(&mut connection)), [(3, NO_ERROR)]);
+
+    // Nor beyond what the stream window lets the client send
+    connection.receive(&post(5, "65535"));
+    connection.receive(&frame(DATA_FRAME_TYPE, 0, 5, &[b'x'; 200]));
+    assert!(resets(&output_frames(&mut connection)).is_empty());
+    assert_eq!(connection.streams.draining(), 1);
+    for _ in 0..3 {
+        connection.receive(&frame(DATA_FRAME_TYPE, 0, 5, &[b'x'; 16384]));
+    }
+    connection.receive(&frame(DATA_FRAME_TYPE, END_STREAM, 5, &[b'x'; 65335 - 3 * 16384]));
+    connection.receive(&post(7, "65536"));
+    assert_eq!(resets(&output_frames(&mut connection)), [(7, NO_ERROR)]);
+    assert!(!connection.busy());
+}
+
+// A file of `length` bytes below a fresh root directory
+#[cfg(feature = "static-files")]
+fn file_root(name: &str, length: usize) -> std::path::PathBuf {
+    let root = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("file.bin"), vec![b'a'; length]).unwrap();
+    fs::canonicalize(root).unwrap()
+}
+
+#[cfg(feature = "static-files")]
+fn open_windows(connection: &mut Connection, stream_id: u32, increment: u32) {
+    connection.receive(&window_update(0, increment));
+    connection.receive(&window_update(stream_id, increment));
+}
+
+#[cfg(feature = "static-files")]
+#[test]
+fn files_are_read_as_they_are_sent() {
+    let root = file_root("read-as-sent", 200_000);
+    let mut connection = started(ServerConfig { root: Some(root.clone()), ..config(SpecLevel::Rfc9113) });
+
+    let block = request_block("GET", "/file.bin", &[]);
+    connection.receive(&frame(HEADERS_FRAME_TYPE, END_HEADERS | END_STREAM, 1, &block));
+    let sent: usize = data_lengths(&output_frames(&mut connection)).iter().sum();
+    assert_eq!(sent, DEFAULT_WINDOW_SIZE as usize);
+
+    // What is still unsent comes from the file as it is now
+    let mut file = fs::OpenOptions::new().write(true).open(root.join("file.bin")).unwrap();
+    file.write_all(&[b'b'; 200_000]).unwrap();
+    open_windows(&mut connection, 1, 1_000_000);
+    assert_eq!(body(&output_frames(&mut connection), 1), vec![b'b'; 200_000 - sent]);
+    assert!(!connection.busy());
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[cfg(feature = "static-files")]
+#[test]
+fn a_file_cut_short_resets_its_stream() {
+    let root = file_root("cut-short", 200_000);
+    let mut connection = started(ServerConfig { root: Some(root.clone()), ..config(SpecLevel::Rfc9113) });
+
+    let block = request_block("GET", "/file.bin", &[]);
+    connection.receive(&frame(HEADERS_FRAME_TYPE, END_HEADERS | END_STREAM, 1, &block));
+    output_frames(&mut connection);
+    fs::write(root.join("file.bin"), b"").unwrap();
+    open_windows(&mut connection, 1, 1_000_000);
+    assert_eq!(resets(&output_frames(&mut connection)), [(1, crate::frame::INTERNAL_ERROR)]);
+    assert!(!connection.busy());
+    assert!(!connection.is_closed());
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+// Walk through the whole stream id space without sending billions of
+// requests: each request skips far ahead, and the last one uses the
+// highest id there is
+#[test]
+fn stream_ids_run_out() {
+    const STRIDE: u32 = 2 * 3 * 7 * 11 * 31 * 151;
+    assert_eq!((MAX_STREAM_ID - 1) % STRIDE, 0);
+
+    let mut connection = started(config(SpecLevel::Rfc9113));
+    let mut stream_id = 1;
+    loop {
+        if stream_id % 3 == 0 {
+            let block = request_block("POST", "/echo", &[("content-length", "4")]);
+            connection.receive(&frame(HEADERS_FRAME_TYPE, END_HEADERS, stream_id, &block));
+            connection.receive(&frame(RST_STREAM_FRAME_TYPE, 0, stream_id, &CANCEL.to_be_bytes()));
+        } else {
+            let block = request_block("GET", "/", &[]);
+            connection.receive(&frame(HEADERS_FRAME_TYPE, END_HEADERS | END_STREAM, stream_id, &block));
+        }
+        let frames = output_frames(&mut connection);
+        assert!(!connection.busy());
+        assert_eq!(connection.streams.draining(), 0);
+        if stream_id == MAX_STREAM_ID {
+            assert_eq!(goaway(&frames), Some((MAX_STREAM_ID, NO_ERROR)));
+            break;
+        }
+        assert_eq!(goaway(&frames), None);
+        stream_id += STRIDE;
+    }
+
+    // The last stream was answered, so nothing keeps the connection open
+    assert!(connection.is_closed());
+}
+
+// Frames of a connection the server gave up on, after `pings` PING frames
+fn flood(config: ServerConfig, pings: usize) -> Vec<Received> {
+    let mut client = Client::with_config(config);
+    for _ in 0..pings {
+        client.send(&frame(PING_FRAME_TYPE, 0, 0, b"flooding"));
+    }
+    client.finish()
+}
+
+fn ping_acks(frames: &[Received]) -> usize {
+    frames.iter().filter(|(header, _)| header.type_ == PING_FRAME_TYPE).count()
+}
+
+#[test]
+fn connections_may_send_a_limited_number_of_frames() {
+    // Counting starts after the SETTINGS that opens the connection
+    let frames = flood(ServerConfig { max_frames: 10, ..config(SpecLevel::Rfc9113) }, 20);
+    assert_eq!(ping_acks(&frames), 10);
+    assert_eq!(goaway(&frames), Some((0, ENHANCE_YOUR_CALM)));
+}
+
+#[test]
+fn connections_may_send_frames_at_a_limited_rate() {
+    // One frame per second allows 10 over the rate window
+    let frames = flood(ServerConfig { max_frame_rate: 1, ..config(SpecLevel::Rfc9113) }, 20);
+    assert_eq!(ping_acks(&frames), 10);
+    assert_eq!(goaway(&frames), Some((0, ENHANCE_YOUR_CALM)));
+
+    let frames = flood(ServerConfig { max_frame_rate: 1, ..config(SpecLevel::Rfc9113) }, 10);
+    assert_eq!(ping_acks(&frames), 10);
+    assert_eq!(goaway(&frames), None);
+}
+
+#[test]
+fn rapid_resets_are_caught_by_the_frame_rate() {
+    // Each stream is opened and cancelled at once, so that no request is
+    // ever open for long
+    let mut client = Client::with_config(ServerConfig { max_frame_rate: 10, ..config(SpecLevel::Rfc9113) });
+    for stream_id in (1..200).step_by(2) {
+        client.request(stream_id, "POST", "/echo", &[], false);
+        client.send(&frame(RST_STREAM_FRAME_TYPE, 0, stream_id, &CANCEL.to_be_bytes()));
+    }
+    let (_, error_code) = goaway(&client.finish()).unwrap();
+    assert_eq!(error_code, ENHANCE_YOUR_CALM);
+}
+
+#[test]
+fn priority_frames_are_accepted() {
+    let mut client = Client::new();
+
+    // On an idle stream, on one that is about to open, and on an idle
+    // stream depending on itself, which is only logged
+    client.send(&frame(PRIORITY_FRAME_TYPE, 0, 3, &[0, 0, 0, 0, 15]));
+    client.send(&frame(PRIORITY_FRAME_TYPE, 0, 1, &[0x80, 0, 0, 3, 255]));
+    client.request(1, "GET", "/", &[], true);
+    client.send(&frame(PRIORITY_FRAME_TYPE, 0, 5, &[0, 0, 0, 5, 0]));
+    let frames = client.finish();
+    assert_eq!(responses(&frames), 1);
+    assert!(resets(&frames).is_empty());
+    assert_eq!(goaway(&frames), None);
+}
+
+#[test]
+fn streams_beyond_the_limit_are_refused() {
+    let mut client = Client::with_config(ServerConfig { max_concurrent_streams: 2, ..config(SpecLevel::Rfc9113) });
+    client.request(1, "POST", "/echo", &[], false);
+    client.request(3, "POST", "/echo", &[], false);
+    client.request(5, "GET", "/", &[], true);
+    assert_eq!(resets(&client.frames_until(RST_STREAM_FRAME_TYPE)), [(5, REFUSED_STREAM)]);
+
+    // Once a stream is done, its place is free again
+    client.data(1, b"done", true);
+    client.response(1);
+    client.request(7, "GET", "/", &[], true);
+    client.response(7);
+    assert_eq!(client.status(7), Some(&b"200"[..]));
+    client.finish();
+}
+
+#[test]
+fn new_streams_need_a_higher_odd_id() {
+    for stream_id in [2, 3, 1] {
+        let mut client = Client::new();
+        client.request(3, "GET", "/", &[], true);
+        client.request(stream_id, "GET", "/", &[], true);
+        assert_eq!(goaway(&client.finish()), Some((3, PROTOCOL_ERROR)), "stream {}", stream_id);
+    }
+}
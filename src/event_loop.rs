@@ -0,0 +1,414 @@
+// Serve every connection from a single thread: the sockets are
+// non-blocking, and poll(2) tells which of them can be read or written
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::os::raw::{c_int, c_short, c_ulong};
+use std::time::{Duration, Instant};
+
+use crate::config::ServerConfig;
+use crate::connection::Connection;
+use crate::shutdown::{shutdown_requested, SHUTDOWN_POLL_INTERVAL};
+use crate::{out_of_buffers, LINGER_TIMEOUT, MAX_LINGER_BYTES, MAX_WRITE_RETRIES, WRITE_RETRY_DELAY};
+
+#[repr(C)]
+struct PollFd {
+    fd: c_int,
+    events: c_short,
+    revents: c_short,
+}
+
+extern "C" {
+    fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+}
+
+const POLLIN: c_short = 0x1;
+const POLLOUT: c_short = 0x4;
+const POLLERR: c_short = 0x8;
+const POLLHUP: c_short = 0x10;
+
+// Output a client may leave unread before we stop reading from it, so
+// that a client which never reads cannot make us queue without bound
+const MAX_QUEUED_OUTPUT: usize = 1024 * 1024;
+
+enum ClientState {
+    // Exchanging data with the client
+    Open,
+    // The connection is over; its last output gets until the deadline to
+    // be written
+    Flushing { deadline: Instant },
+    // The connection is over, and what the client still sends is read
+    // away until the deadline; see `linger` in the threaded server
+    Lingering { deadline: Instant, discarded: usize },
+    // The socket can be closed
+    Finished,
+}
+
+struct Client {
+    stream: TcpStream,
+    connection: Connection,
+    state: ClientState,
+    output: Output,
+    // Bytes written on one wakeup before the other connections get a turn
+    write_budget: usize,
+}
+
+// Bytes waiting for the socket to accept them, from `written` on
+struct Output {
+    bytes: Vec<u8>,
+    written: usize,
+    // Writes in a row that failed for lack of kernel buffers, and when to
+    // try again. Until then the socket is not polled for POLLOUT, which it
+    // may well report all along
+    write_retries: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Output {
+    fn new() -> Self {
+        Output {
+            bytes: Vec::new(),
+            written: 0,
+            write_retries: 0,
+            retry_at: None,
+        }
+    }
+
+    fn is_pending(&self) -> bool {
+        self.written < self.bytes.len()
+    }
+
+    // Write as much as `writer` takes, up to `budget` bytes: true once all
+    // of it is out, false while the socket is full, a retry is not due yet
+    // or the budget is spent. More comes from `produce` only once the bytes
+    // before it are out: taking the connection's output is what lets a
+    // paused response produce its next slice
+    fn write_to(
+        &mut self,
+        writer: &mut impl Write,
+        mut budget: usize,
+        mut produce: impl FnMut() -> Vec<u8>,
+    ) -> io::Result<bool> {
+        if self.retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+            return Ok(false);
+        }
+        self.retry_at = None;
+        loop {
+            if !self.is_pending() {
+                self.bytes = produce();
+                self.written = 0;
+                if self.bytes.is_empty() {
+                    return Ok(true);
+                }
+            }
+            if budget == 0 {
+                return Ok(false);
+            }
+
+            let end = self.written + budget.min(self.bytes.len() - self.written);
+            match writer.write(&self.bytes[self.written..end]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.written += n;
+                    budget -= n;
+                    self.write_retries = 0;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                // Resume at the same byte once the delay passed
+                Err(e) if out_of_buffers(&e) && self.write_retries < MAX_WRITE_RETRIES => {
+                    self.retry_at = Some(Instant::now() + WRITE_RETRY_DELAY * 2u32.pow(self.write_retries));
+                    self.write_retries += 1;
+                    return Ok(false);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Client {
+    fn new(stream: TcpStream, config: &ServerConfig) -> Self {
+        Client {
+            stream,
+            connection: Connection::new(config.clone()),
+            state: ClientState::Open,
+            output: Output::new(),
+            write_budget: config.write_budget,
+        }
+    }
+
+    // Events to wait for
+    fn interest(&self) -> c_short {
+        let mut events = 0;
+        match self.state {
+            ClientState::Open if !self.connection.is_closed() && self.queued_output() < MAX_QUEUED_OUTPUT => {
+                events |= POLLIN;
+            }
+            ClientState::Lingering { .. } => events |= POLLIN,
+            _ => {}
+        }
+        if self.output.is_pending() && self.output.retry_at.is_none() {
+            events |= POLLOUT;
+        }
+        events
+    }
+
+    // Output not written yet, ours and the connection's
+    fn queued_output(&self) -> usize {
+        self.output.bytes.len() - self.output.written + self.connection.pending_output()
+    }
+
+    fn on_events(&mut self, revents: c_short) {
+        if revents & (POLLIN | POLLERR | POLLHUP) != 0 {
+            self.read();
+        }
+        self.write();
+    }
+
+    fn on_tick(&mut self) {
+        if let ClientState::Flushing { deadline } | ClientState::Lingering { deadline, .. } = self.state {
+            if Instant::now() >= deadline {
+                self.state = ClientState::Finished;
+            }
+        }
+
+        self.connection.on_tick();
+        self.write();
+    }
+
+    fn read(&mut self) {
+        let mut buffer = [0; 16384];
+        match (self.stream.read(&mut buffer), &mut self.state) {
+            (Err(e), _) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {}
+            (Ok(n), ClientState::Lingering { discarded, .. }) if n > 0 => {
+                *discarded += n;
+                if *discarded >= MAX_LINGER_BYTES {
+                    self.state = ClientState::Finished;
+                }
+            }
+            (_, ClientState::Lingering { .. }) => self.state = ClientState::Finished,
+            (Ok(0), _) => self.connection.receive_eof(),
+            (Ok(n), _) => self.connection.receive(&buffer[..n]),
+            (Err(e), _) => self.connection.fail(e),
+        }
+    }
+
+    // Write what the connection produced, as much as the socket takes and
+    // the budget allows. Once the connection is over and its output is
+    // out, start lingering or finish
+    fn write(&mut self) {
+        match self.state {
+            ClientState::Open if self.connection.is_closed() => {
+                self.state = ClientState::Flushing {
+                    deadline: Instant::now() + LINGER_TIMEOUT,
+                };
+            }
+            ClientState::Open | ClientState::Flushing { .. } => {}
+            ClientState::Lingering { .. } | ClientState::Finished => return,
+        }
+
+        let connection = &mut self.connection;
+        match self.output.write_to(&mut self.stream, self.write_budget, || connection.take_output()) {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => return self.write_failed(e),
+        }
+
+        if let ClientState::Flushing { .. } = self.state {
+            self.state = if self.connection.lingers() && self.stream.shutdown(Shutdown::Write).is_ok() {
+                ClientState::Lingering {
+                    deadline: Instant::now() + LINGER_TIMEOUT,
+                    discarded: 0,
+                }
+            } else {
+                ClientState::Finished
+            };
+        }
+    }
+
+    fn write_failed(&mut self, error: io::Error) {
+        if self.connection.is_closed() {
+            if self.connection.lingers() {
+                eprintln!("Failed to send GOAWAY");
+            }
+        } else {
+            self.connection.fail(error);
+        }
+        self.state = ClientState::Finished;
+    }
+}
+
+// Accept connections and serve them all until a shutdown request, which
+// stops accepting; the open connections then drain as in the threaded
+// server
+pub fn run(listener: TcpListener, config: &ServerConfig) {
+    if let Err(e) = listener.set_nonblocking(true) {
+        eprintln!("Failed to set up listener: {}", e);
+        return;
+    }
+
+    let mut listener = Some(listener);
+    let mut clients: Vec<Client> = Vec::new();
+    let mut next_tick = Instant::now();
+    // Longest time spent handling one round of events, which the write
+    // budget keeps short while huge responses are sent
+    let mut longest_dispatch = Duration::ZERO;
+    loop {
+        if shutdown_requested() && listener.is_some() {
+            // Refuse new connections, and give the open ones time to drain
+            listener = None;
+            println!("Shutting down, waiting for {} connection(s)", clients.len());
+        }
+        if listener.is_none() && clients.is_empty() {
+            println!("Longest event loop dispatch: {:?}", longest_dispatch);
+            break;
+        }
+
+        // The listener comes first; a closed one is skipped with a
+        // negative descriptor
+        let mut fds = Vec::with_capacity(clients.len() + 1);
+        fds.push(PollFd {
+            fd: listener.as_ref().map_or(-1, |listener| listener.as_raw_fd()),
+            events: POLLIN,
+            revents: 0,
+        });
+        for client in &clients {
+            fds.push(PollFd {
+                fd: client.stream.as_raw_fd(),
+                events: client.interest(),
+                revents: 0,
+            });
+        }
+
+        // Wake up regularly for timeouts even when no socket is ready, and
+        // in time for the next write retry. A signal interrupts the wait,
+        // which is fine
+        let now = Instant::now();
+        let wait = clients
+            .iter()
+            .filter_map(|client| client.output.retry_at)
+            .map(|retry_at| retry_at.saturating_duration_since(now))
+            .fold(SHUTDOWN_POLL_INTERVAL, Duration::min);
+        let timeout = wait.as_millis() as c_int;
+        if unsafe { poll(fds.as_mut_ptr(), fds.len() as c_ulong, timeout) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != ErrorKind::Interrupted {
+                eprintln!("Failed to poll connections: {}", e);
+                return;
+            }
+            continue;
+        }
+
+        let dispatch_start = Instant::now();
+        for (client, fd) in clients.iter_mut().zip(&fds[1..]) {
+            if fd.revents != 0 {
+                client.on_events(fd.revents);
+            } else if client.output.retry_at.is_some_and(|retry_at| Instant::now() >= retry_at) {
+                client.write();
+            }
+        }
+
+        if fds[0].revents & POLLIN != 0 {
+            if let Some(listener) = &listener {
+                accept(listener, config, &mut clients);
+            }
+        }
+
+        if Instant::now() >= next_tick {
+            for client in &mut clients {
+                client.on_tick();
+            }
+            next_tick = Instant::now() + SHUTDOWN_POLL_INTERVAL;
+        }
+        clients.retain(|client| !matches!(client.state, ClientState::Finished));
+        longest_dispatch = longest_dispatch.max(dispatch_start.elapsed());
+    }
+}
+
+// Take every connection waiting on the listener
+fn accept(listener: &TcpListener, config: &ServerConfig, clients: &mut Vec<Client>) {
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => match stream.set_nonblocking(true) {
+                Ok(()) => clients.push(Client::new(stream, config)),
+                Err(e) => eprintln!("Failed to set up connection: {}", e),
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::tests::ShortWriter;
+
+    // Hands out the chunks one by one, then nothing
+    fn chunks(chunks: Vec<Vec<u8>>) -> impl FnMut() -> Vec<u8> {
+        let mut chunks = chunks.into_iter();
+        move || chunks.next().unwrap_or_default()
+    }
+
+    #[test]
+    fn queued_output_resumes_where_buffers_ran_out() {
+        let bytes: Vec<u8> = (0..48).collect();
+        for fail_at in 0..40 {
+            let mut writer = ShortWriter::new(7, fail_at, 1);
+            let mut output = Output::new();
+            let mut produce = chunks(vec![bytes[..40].to_vec(), bytes[40..].to_vec()]);
+            assert!(!output.write_to(&mut writer, usize::MAX, &mut produce).unwrap());
+            assert_eq!(writer.written.len(), fail_at);
+
+            // What the connection produced meanwhile goes out after it,
+            // once the retry is due
+            output.retry_at = Some(Instant::now());
+            assert!(output.write_to(&mut writer, usize::MAX, &mut produce).unwrap());
+            assert_eq!(writer.written, bytes, "failing at {}", fail_at);
+        }
+    }
+
+    #[test]
+    fn queued_output_gives_up_after_its_retries() {
+        let mut writer = ShortWriter::new(7, 10, MAX_WRITE_RETRIES + 1);
+        let mut output = Output::new();
+        let mut produce = chunks(vec![(0..40).collect()]);
+        for _ in 0..MAX_WRITE_RETRIES {
+            assert!(!output.write_to(&mut writer, usize::MAX, &mut produce).unwrap());
+            output.retry_at = Some(Instant::now());
+        }
+        assert!(out_of_buffers(&output.write_to(&mut writer, usize::MAX, &mut produce).unwrap_err()));
+        assert_eq!(writer.written.len(), 10);
+    }
+
+    #[test]
+    fn writes_stop_at_the_budget_and_resume_on_the_next_wakeup() {
+        let bytes: Vec<u8> = (0..25).collect();
+        let mut writer = ShortWriter::new(usize::MAX, usize::MAX, 0);
+        let mut output = Output::new();
+        let taken = Cell::new(0);
+        let mut produce = chunks(vec![bytes[..15].to_vec(), bytes[15..].to_vec()]);
+        let mut produce = || {
+            taken.set(taken.get() + 1);
+            produce()
+        };
+        assert!(!output.write_to(&mut writer, 10, &mut produce).unwrap());
+        assert_eq!(writer.written, bytes[..10]);
+
+        // The second chunk is only taken once the first is out
+        assert!(!output.write_to(&mut writer, 10, &mut produce).unwrap());
+        assert_eq!(writer.written, bytes[..20]);
+        assert_eq!(taken.get(), 2);
+
+        assert!(output.write_to(&mut writer, 10, &mut produce).unwrap());
+        assert_eq!(writer.written, bytes);
+        assert_eq!(taken.get(), 3);
+    }
+}
@@ -0,0 +1,787 @@
+// HTTP/2 frames (RFC 9113 section 6): the 9-byte frame header, and typed
+// frames parsed from and serialized to their wire layout
+
+use std::fmt;
+use std::io;
+use std::mem;
+
+// Constants for frame types
+pub const DATA_FRAME_TYPE: u8 = 0x00;
+pub const HEADERS_FRAME_TYPE: u8 = 0x01;
+pub const PRIORITY_FRAME_TYPE: u8 = 0x02;
+pub const RST_STREAM_FRAME_TYPE: u8 = 0x03;
+pub const SETTINGS_FRAME_TYPE: u8 = 0x04;
+pub const PUSH_PROMISE_FRAME_TYPE: u8 = 0x05;
+pub const PING_FRAME_TYPE: u8 = 0x06;
+pub const GOAWAY_FRAME_TYPE: u8 = 0x07;
+pub const WINDOW_UPDATE_FRAME_TYPE: u8 = 0x08;
+pub const CONTINUATION_FRAME_TYPE: u8 = 0x09;
+
+// Constants for frame flags
+pub const ACK_FLAG: u8 = 0x01;
+pub const END_STREAM_FLAG: u8 = 0x01;
+pub const END_HEADERS_FLAG: u8 = 0x04;
+pub const PADDED_FLAG: u8 = 0x08;
+pub const PRIORITY_FLAG: u8 = 0x20;
+
+// Constants for error codes
+pub const NO_ERROR: u32 = 0x00;
+pub const PROTOCOL_ERROR: u32 = 0x01;
+pub const INTERNAL_ERROR: u32 = 0x02;
+pub const FLOW_CONTROL_ERROR: u32 = 0x03;
+pub const STREAM_CLOSED: u32 = 0x05;
+pub const FRAME_SIZE_ERROR: u32 = 0x06;
+pub const REFUSED_STREAM: u32 = 0x07;
+pub const COMPRESSION_ERROR: u32 = 0x09;
+pub const ENHANCE_YOUR_CALM: u32 = 0x0b;
+
+// RFC 7540 error code, as carried by RST_STREAM and GOAWAY
+pub type ErrorCode = u32;
+
+// Reason a connection has to be closed. Everything but an I/O failure is
+// reported to the client in a GOAWAY frame; stream errors never get here,
+// they are answered with RST_STREAM where they are detected
+#[derive(Debug)]
+pub enum Http2Error {
+    // Protocol violation with the error code to report
+    Protocol(ErrorCode),
+    // Protocol violation explained to the client in the GOAWAY debug data
+    Violation(ErrorCode, &'static str),
+    // Frame whose length its type does not allow
+    FrameSize,
+    // Header block the HPACK decoder rejected
+    Compression,
+    // Reading from or writing to the socket failed
+    Io(io::Error),
+}
+
+impl Http2Error {
+    pub fn error_code(&self) -> ErrorCode {
+        match self {
+            Http2Error::Protocol(error_code) | Http2Error::Violation(error_code, _) => *error_code,
+            Http2Error::FrameSize => FRAME_SIZE_ERROR,
+            Http2Error::Compression => COMPRESSION_ERROR,
+            Http2Error::Io(_) => INTERNAL_ERROR,
+        }
+    }
+
+    // What to put in the GOAWAY debug data
+    pub fn debug_data(&self) -> &'static str {
+        match self {
+            Http2Error::Violation(_, reason) => reason,
+            _ => "",
+        }
+    }
+}
+
+impl fmt::Display for Http2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Http2Error::Protocol(error_code) => write!(f, "protocol error (code {})", error_code),
+            Http2Error::Violation(error_code, reason) => write!(f, "{} (code {})", reason, error_code),
+            Http2Error::FrameSize => write!(f, "frame size error"),
+            Http2Error::Compression => write!(f, "compression error"),
+            Http2Error::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for Http2Error {
+    fn from(e: io::Error) -> Self {
+        Http2Error::Io(e)
+    }
+}
+
+// Frame header structure
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameHeader {
+    pub length: u32,
+    pub type_: u8,
+    pub flags: u8,
+    pub stream_id: u32,
+}
+
+impl FrameHeader {
+    pub fn from_bytes(bytes: &[u8; 9]) -> Self {
+        let length = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        let type_ = bytes[3];
+        let flags = bytes[4];
+        let stream_id = u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]) & 0x7FFFFFFF;
+
+        FrameHeader {
+            length,
+            type_,
+            flags,
+            stream_id,
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; 9] {
+        let length = self.length.to_be_bytes();
+        let stream_id = (self.stream_id & 0x7FFFFFFF).to_be_bytes();
+
+        [
+            length[1], length[2], length[3],
+            self.type_,
+            self.flags,
+            stream_id[0], stream_id[1], stream_id[2], stream_id[3],
+        ]
+    }
+
+    // Name of the frame type, for logging
+    pub fn type_name(&self) -> &'static str {
+        match self.type_ {
+            DATA_FRAME_TYPE => "DATA",
+            HEADERS_FRAME_TYPE => "HEADERS",
+            PRIORITY_FRAME_TYPE => "PRIORITY",
+            RST_STREAM_FRAME_TYPE => "RST_STREAM",
+            SETTINGS_FRAME_TYPE => "SETTINGS",
+            PUSH_PROMISE_FRAME_TYPE => "PUSH_PROMISE",
+            PING_FRAME_TYPE => "PING",
+            GOAWAY_FRAME_TYPE => "GOAWAY",
+            WINDOW_UPDATE_FRAME_TYPE => "WINDOW_UPDATE",
+            CONTINUATION_FRAME_TYPE => "CONTINUATION",
+            _ => "unknown",
+        }
+    }
+}
+
+// Stream priority, carried by PRIORITY frames and by HEADERS frames
+// with the PRIORITY flag
+#[derive(Debug, PartialEq)]
+pub struct PrioritySpec {
+    pub exclusive: bool,
+    pub dependency: u32,
+    pub weight: u16, // 1..=256, the wire value plus one
+}
+
+impl PrioritySpec {
+    fn read(reader: &mut ByteReader) -> Result<Self, Http2Error> {
+        let dependency = reader.read_u32("stream dependency")?;
+        let weight = u16::from(reader.read_u8("weight")?) + 1;
+
+        Ok(PrioritySpec {
+            exclusive: dependency & 0x80000000 != 0,
+            dependency: dependency & 0x7FFFFFFF,
+            weight,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; 5] {
+        let mut dependency = self.dependency & 0x7FFFFFFF;
+        if self.exclusive {
+            dependency |= 0x80000000;
+        }
+        let dependency = dependency.to_be_bytes();
+
+        [dependency[0], dependency[1], dependency[2], dependency[3], (self.weight - 1) as u8]
+    }
+}
+
+// A frame with its payload parsed into fields. Padding is kept as its
+// length only: the padding bytes themselves carry nothing and are zero
+// when serialized
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    Data {
+        stream_id: u32,
+        end_stream: bool,
+        padding: Option<u8>,
+        data: Vec<u8>,
+    },
+    Headers {
+        stream_id: u32,
+        end_stream: bool,
+        end_headers: bool,
+        padding: Option<u8>,
+        priority: Option<PrioritySpec>,
+        fragment: Vec<u8>,
+    },
+    Priority {
+        stream_id: u32,
+        priority: PrioritySpec,
+    },
+    RstStream {
+        stream_id: u32,
+        error_code: ErrorCode,
+    },
+    Settings {
+        ack: bool,
+        settings: Vec<(u16, u32)>,
+    },
+    PushPromise {
+        stream_id: u32,
+        end_headers: bool,
+        padding: Option<u8>,
+        promised_stream_id: u32,
+        fragment: Vec<u8>,
+    },
+    Ping {
+        ack: bool,
+        data: [u8; 8],
+    },
+    Goaway {
+        last_stream_id: u32,
+        error_code: ErrorCode,
+        debug_data: Vec<u8>,
+    },
+    WindowUpdate {
+        stream_id: u32,
+        increment: u32,
+    },
+    Continuation {
+        stream_id: u32,
+        end_headers: bool,
+        fragment: Vec<u8>,
+    },
+    // A frame type we do not know; its flags and payload are uninterpreted
+    Unknown {
+        header: FrameHeader,
+        payload: Vec<u8>,
+    },
+}
+
+impl Frame {
+    // Parse the payload of a frame. Fixed-size payloads must have their
+    // exact length, and padding must fit in the payload
+    pub fn parse(header: FrameHeader, payload: &[u8]) -> Result<Frame, Http2Error> {
+        if payload.len() != header.length as usize {
+            eprintln!("Frame payload of {} bytes, its header says {}", payload.len(), header.length);
+            return Err(Http2Error::FrameSize);
+        }
+
+        let stream_id = header.stream_id;
+        let has_flag = |flag: u8| header.flags & flag == flag;
+        let mut reader = ByteReader::new(header.type_name(), payload);
+
+        let frame = match header.type_ {
+            DATA_FRAME_TYPE => Frame::Data {
+                stream_id,
+                end_stream: has_flag(END_STREAM_FLAG),
+                padding: strip_padding(&header, &mut reader)?,
+                data: reader.read_rest().to_vec(),
+            },
+            HEADERS_FRAME_TYPE => {
+                let padding = strip_padding(&header, &mut reader)?;
+
+                // With the PRIORITY flag, the fragment is preceded by 5 bytes
+                // of priority information that must not reach the HPACK decoder
+                let priority = if has_flag(PRIORITY_FLAG) {
+                    Some(PrioritySpec::read(&mut reader)?)
+                } else {
+                    None
+                };
+
+                Frame::Headers {
+                    stream_id,
+                    end_stream: has_flag(END_STREAM_FLAG),
+                    end_headers: has_flag(END_HEADERS_FLAG),
+                    padding,
+                    priority,
+                    fragment: reader.read_rest().to_vec(),
+                }
+            }
+            PRIORITY_FRAME_TYPE => {
+                reader.expect_length(5)?;
+                Frame::Priority {
+                    stream_id,
+                    priority: PrioritySpec::read(&mut reader)?,
+                }
+            }
+            RST_STREAM_FRAME_TYPE => {
+                reader.expect_length(4)?;
+                Frame::RstStream {
+                    stream_id,
+                    error_code: reader.read_u32("error code")?,
+                }
+            }
+            SETTINGS_FRAME_TYPE => {
+                if !payload.len().is_multiple_of(6) {
+                    eprintln!("SETTINGS frame with invalid length {}", payload.len());
+                    return Err(Http2Error::FrameSize);
+                }
+                Frame::Settings {
+                    ack: has_flag(ACK_FLAG),
+                    settings: read_settings(&mut reader)?,
+                }
+            }
+            PUSH_PROMISE_FRAME_TYPE => Frame::PushPromise {
+                stream_id,
+                end_headers: has_flag(END_HEADERS_FLAG),
+                padding: strip_padding(&header, &mut reader)?,
+                promised_stream_id: reader.read_u31("promised stream id")?,
+                fragment: reader.read_rest().to_vec(),
+            },
+            PING_FRAME_TYPE => {
+                reader.expect_length(8)?;
+                Frame::Ping {
+                    ack: has_flag(ACK_FLAG),
+                    data: reader.read_array("opaque data")?,
+                }
+            }
+            GOAWAY_FRAME_TYPE => Frame::Goaway {
+                last_stream_id: reader.read_u31("last stream id")?,
+                error_code: reader.read_u32("error code")?,
+                debug_data: reader.read_rest().to_vec(),
+            },
+            WINDOW_UPDATE_FRAME_TYPE => {
+                reader.expect_length(4)?;
+                Frame::WindowUpdate {
+                    stream_id,
+                    // The reserved bit is ignored
+                    increment: reader.read_u31("window size increment")?,
+                }
+            }
+            CONTINUATION_FRAME_TYPE => Frame::Continuation {
+                stream_id,
+                end_headers: has_flag(END_HEADERS_FLAG),
+                fragment: payload.to_vec(),
+            },
+            _ => Frame::Unknown {
+                header,
+                payload: payload.to_vec(),
+            },
+        };
+
+        Ok(frame)
+    }
+
+    // The frame header followed by the payload
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut flags = 0;
+        let mut payload = Vec::new();
+
+        let (type_, stream_id) = match self {
+            Frame::Data { stream_id, end_stream, padding, data } => {
+                if *end_stream {
+                    flags |= END_STREAM_FLAG;
+                }
+                push_padded(&mut payload, &mut flags, *padding, &[data]);
+                (DATA_FRAME_TYPE, *stream_id)
+            }
+            Frame::Headers { stream_id, end_stream, end_headers, padding, priority, fragment } => {
+                if *end_stream {
+                    flags |= END_STREAM_FLAG;
+                }
+                if *end_headers {
+                    flags |= END_HEADERS_FLAG;
+                }
+                match priority {
+                    Some(priority) => {
+                        flags |= PRIORITY_FLAG;
+                        push_padded(&mut payload, &mut flags, *padding, &[&priority.to_bytes(), fragment]);
+                    }
+                    None => push_padded(&mut payload, &mut flags, *padding, &[fragment]),
+                }
+                (HEADERS_FRAME_TYPE, *stream_id)
+            }
+            Frame::Priority { stream_id, priority } => {
+                payload.extend_from_slice(&priority.to_bytes());
+                (PRIORITY_FRAME_TYPE, *stream_id)
+            }
+            Frame::RstStream { stream_id, error_code } => {
+                payload.extend_from_slice(&error_code.to_be_bytes());
+                (RST_STREAM_FRAME_TYPE, *stream_id)
+            }
+            Frame::Settings { ack, settings } => {
+                if *ack {
+                    flags |= ACK_FLAG;
+                }
+                for (key, value) in settings {
+                    payload.extend_from_slice(&key.to_be_bytes());
+                    payload.extend_from_slice(&value.to_be_bytes());
+                }
+                (SETTINGS_FRAME_TYPE, 0)
+            }
+            Frame::PushPromise { stream_id, end_headers, padding, promised_stream_id, fragment } => {
+                if *end_headers {
+                    flags |= END_HEADERS_FLAG;
+                }
+                let promised_stream_id = (promised_stream_id & 0x7FFFFFFF).to_be_bytes();
+                push_padded(&mut payload, &mut flags, *padding, &[&promised_stream_id, fragment]);
+                (PUSH_PROMISE_FRAME_TYPE, *stream_id)
+            }
+            Frame::Ping { ack, data } => {
+                if *ack {
+                    flags |= ACK_FLAG;
+                }
+                payload.extend_from_slice(data);
+                (PING_FRAME_TYPE, 0)
+            }
+            Frame::Goaway { last_stream_id, error_code, debug_data } => {
+                payload.extend_from_slice(&(last_stream_id & 0x7FFFFFFF).to_be_bytes());
+                payload.extend_from_slice(&error_code.to_be_bytes());
+                payload.extend_from_slice(debug_data);
+                (GOAWAY_FRAME_TYPE, 0)
+            }
+            Frame::WindowUpdate { stream_id, increment } => {
+                payload.extend_from_slice(&(increment & 0x7FFFFFFF).to_be_bytes());
+                (WINDOW_UPDATE_FRAME_TYPE, *stream_id)
+            }
+            Frame::Continuation { stream_id, end_headers, fragment } => {
+                if *end_headers {
+                    flags |= END_HEADERS_FLAG;
+                }
+                payload.extend_from_slice(fragment);
+                (CONTINUATION_FRAME_TYPE, *stream_id)
+            }
+            Frame::Unknown { header, payload: unknown } => {
+                flags = header.flags;
+                payload.extend_from_slice(unknown);
+                (header.type_, header.stream_id)
+            }
+        };
+
+        let header = FrameHeader {
+            length: payload.len() as u32,
+            type_,
+            flags,
+            stream_id,
+        };
+
+        let mut bytes = header.to_bytes().to_vec();
+        bytes.extend(payload);
+        bytes
+    }
+}
+
+// Checked reads from the front of a frame payload. Running out of bytes is
+// a frame size error, logged with the field that did not fit
+struct ByteReader<'a> {
+    frame: &'static str,
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(frame: &'static str, bytes: &'a [u8]) -> Self {
+        ByteReader { frame, bytes }
+    }
+
+    fn remaining(&self) -> usize {
+        self.bytes.len()
+    }
+
+    // Fixed-size frames must have their exact length
+    fn expect_length(&self, length: usize) -> Result<(), Http2Error> {
+        if self.bytes.len() != length {
+            eprintln!("{} frame with invalid length {}", self.frame, self.bytes.len());
+            return Err(Http2Error::FrameSize);
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, length: usize, field: &str) -> Result<&'a [u8], Http2Error> {
+        if length > self.bytes.len() {
+            eprintln!("{} frame too short for its {}", self.frame, field);
+            return Err(Http2Error::FrameSize);
+        }
+        let (bytes, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    fn read_array<const N: usize>(&mut self, field: &str) -> Result<[u8; N], Http2Error> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.read_bytes(N, field)?);
+        Ok(array)
+    }
+
+    fn read_u8(&mut self, field: &str) -> Result<u8, Http2Error> {
+        let [byte] = self.read_array(field)?;
+        Ok(byte)
+    }
+
+    fn read_u16(&mut self, field: &str) -> Result<u16, Http2Error> {
+        Ok(u16::from_be_bytes(self.read_array(field)?))
+    }
+
+    fn read_u32(&mut self, field: &str) -> Result<u32, Http2Error> {
+        Ok(u32::from_be_bytes(self.read_array(field)?))
+    }
+
+    // A stream id or window increment, without the reserved bit
+    fn read_u31(&mut self, field: &str) -> Result<u32, Http2Error> {
+        Ok(self.read_u32(field)? & 0x7FFFFFFF)
+    }
+
+    fn read_rest(&mut self) -> &'a [u8] {
+        mem::take(&mut self.bytes)
+    }
+
+    // Cut `length` bytes off the end, failing when there are not that many
+    fn truncate_end(&mut self, length: usize) -> bool {
+        match self.bytes.len().checked_sub(length) {
+            Some(end) => {
+                self.bytes = &self.bytes[..end];
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn read_settings(reader: &mut ByteReader) -> Result<Vec<(u16, u32)>, Http2Error> {
+    let mut settings = Vec::with_capacity(reader.remaining() / 6);
+    while reader.remaining() > 0 {
+        settings.push((reader.read_u16("setting identifier")?, reader.read_u32("setting value")?));
+    }
+    Ok(settings)
+}
+
+// Split a SETTINGS payload, whose length is a multiple of 6, into its
+// identifier and value pairs
+pub fn parse_settings(payload: &[u8]) -> Vec<(u16, u32)> {
+    read_settings(&mut ByteReader::new("SETTINGS", payload)).unwrap_or_default()
+}
+
+// With the PADDED flag, the first byte is the padding length and the
+// padding itself trails the rest of the payload
+fn strip_padding(header: &FrameHeader, reader: &mut ByteReader) -> Result<Option<u8>, Http2Error> {
+    if header.flags & PADDED_FLAG == 0 {
+        return Ok(None);
+    }
+
+    let pad_length = reader.read_u8("padding length")?;
+    if !reader.truncate_end(usize::from(pad_length)) {
+        eprintln!("{} padding exceeds the frame payload", header.type_name());
+        return Err(Http2Error::Protocol(PROTOCOL_ERROR));
+    }
+    Ok(Some(pad_length))
+}
+
+// Lay out the parts of a payload with optional padding around them
+fn push_padded(payload: &mut Vec<u8>, flags: &mut u8, padding: Option<u8>, parts: &[&[u8]]) {
+    if let Some(pad_length) = padding {
+        *flags |= PADDED_FLAG;
+        payload.push(pad_length);
+    }
+    for part in parts {
+        payload.extend_from_slice(part);
+    }
+    payload.resize(payload.len() + usize::from(padding.unwrap_or(0)), 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parse what `serialize` produced
+    fn reparse(bytes: &[u8]) -> Result<Frame, Http2Error> {
+        let header = FrameHeader::from_bytes(bytes[..9].try_into().unwrap());
+        assert_eq!(header.length as usize, bytes.len() - 9);
+        Frame::parse(header, &bytes[9..])
+    }
+
+    fn header(length: u32, type_: u8, flags: u8) -> FrameHeader {
+        FrameHeader { length, type_, flags, stream_id: 1 }
+    }
+
+    #[test]
+    fn every_frame_survives_a_round_trip() {
+        let priority = || PrioritySpec { exclusive: true, dependency: 3, weight: 201 };
+        let mut frames = Vec::new();
+        for padding in [None, Some(0), Some(7)] {
+            for (end_stream, end_headers) in [(false, false), (true, false), (false, true), (true, true)] {
+                frames.push(Frame::Data { stream_id: 1, end_stream, padding, data: b"body".to_vec() });
+                frames.push(Frame::Headers {
+                    stream_id: 1,
+                    end_stream,
+                    end_headers,
+                    padding,
+                    priority: None,
+                    fragment: b"fields".to_vec(),
+                });
+                frames.push(Frame::Headers {
+                    stream_id: 1,
+                    end_stream,
+                    end_headers,
+                    padding,
+                    priority: Some(priority()),
+                    fragment: Vec::new(),
+                });
+            }
+            for end_headers in [false, true] {
+                frames.push(Frame::PushPromise {
+                    stream_id: 1,
+                    end_headers,
+                    padding,
+                    promised_stream_id: 2,
+                    fragment: b"fields".to_vec(),
+                });
+            }
+        }
+        frames.extend([
+            Frame::Data { stream_id: 0x7fff_ffff, end_stream: false, padding: None, data: Vec::new() },
+            Frame::Priority { stream_id: 5, priority: priority() },
+            Frame::RstStream { stream_id: 5, error_code: ENHANCE_YOUR_CALM },
+            Frame::Settings { ack: false, settings: vec![(0x1, 0), (0x4, 65535), (0xabcd, u32::MAX)] },
+            Frame::Settings { ack: false, settings: Vec::new() },
+            Frame::Settings { ack: true, settings: Vec::new() },
+            Frame::Ping { ack: false, data: *b"12345678" },
+            Frame::Ping { ack: true, data: [0; 8] },
+            Frame::Goaway { last_stream_id: 7, error_code: PROTOCOL_ERROR, debug_data: b"bad".to_vec() },
+            Frame::Goaway { last_stream_id: 0, error_code: NO_ERROR, debug_data: Vec::new() },
+            Frame::WindowUpdate { stream_id: 0, increment: 0x7fff_ffff },
+            Frame::WindowUpdate { stream_id: 9, increment: 1 },
+            Frame::Continuation { stream_id: 1, end_headers: false, fragment: Vec::new() },
+            Frame::Continuation { stream_id: 1, end_headers: true, fragment: b"more".to_vec() },
+            Frame::Unknown { header: header(3, 0xfa, 0xff), payload: b"ext".to_vec() },
+        ]);
+
+        for frame in frames {
+            assert_eq!(reparse(&frame.serialize()).unwrap(), frame);
+        }
+    }
+
+    fn read_priority(bytes: [u8; 5]) -> PrioritySpec {
+        PrioritySpec::read(&mut ByteReader::new("PRIORITY", &bytes)).unwrap()
+    }
+
+    #[test]
+    fn priority_weights_are_one_more_than_on_the_wire() {
+        for (wire, weight) in [(0, 1), (15, 16), (255, 256)] {
+            let spec = read_priority([0, 0, 0, 0, wire]);
+            assert_eq!(spec, PrioritySpec { exclusive: false, dependency: 0, weight });
+            assert_eq!(spec.to_bytes(), [0, 0, 0, 0, wire]);
+        }
+    }
+
+    #[test]
+    fn priority_exclusive_bit_is_not_part_of_the_dependency() {
+        let spec = read_priority([0x80, 0, 0, 0, 0]);
+        assert_eq!(spec, PrioritySpec { exclusive: true, dependency: 0, weight: 1 });
+        assert_eq!(spec.to_bytes(), [0x80, 0, 0, 0, 0]);
+
+        let spec = read_priority([0xff, 0xff, 0xff, 0xff, 255]);
+        assert_eq!(spec, PrioritySpec { exclusive: true, dependency: 0x7fff_ffff, weight: 256 });
+        assert_eq!(spec.to_bytes(), [0xff; 5]);
+
+        let spec = read_priority([0x7f, 0xff, 0xff, 0xff, 0]);
+        assert_eq!(spec, PrioritySpec { exclusive: false, dependency: 0x7fff_ffff, weight: 1 });
+
+        // A dependency beyond 31 bits cannot set the exclusive bit
+        let spec = PrioritySpec { exclusive: false, dependency: 0x8000_0003, weight: 1 };
+        assert_eq!(spec.to_bytes(), [0, 0, 0, 3, 0]);
+    }
+
+    #[test]
+    fn padding_is_zeroes_at_the_end() {
+        let frame = Frame::Data { stream_id: 1, end_stream: true, padding: Some(3), data: b"ab".to_vec() };
+        let bytes = frame.serialize();
+        assert_eq!(&bytes[3..5], [DATA_FRAME_TYPE, END_STREAM_FLAG | PADDED_FLAG]);
+        assert_eq!(&bytes[9..], [3, b'a', b'b', 0, 0, 0]);
+    }
+
+    #[test]
+    fn fixed_size_frames_need_their_exact_length() {
+        let cases = [
+            (PRIORITY_FRAME_TYPE, 5),
+            (RST_STREAM_FRAME_TYPE, 4),
+            (PING_FRAME_TYPE, 8),
+            (WINDOW_UPDATE_FRAME_TYPE, 4),
+        ];
+        for (type_, length) in cases {
+            for wrong in [0, length - 1, length + 1] {
+                let payload = vec![0; wrong];
+                let result = Frame::parse(header(wrong as u32, type_, 0), &payload);
+                assert!(matches!(result, Err(Http2Error::FrameSize)), "type {} length {}", type_, wrong);
+            }
+            let payload = vec![0; length];
+            assert!(Frame::parse(header(length as u32, type_, 0), &payload).is_ok());
+        }
+
+        for length in [1, 5, 7, 11] {
+            let payload = vec![0; length];
+            let result = Frame::parse(header(length as u32, SETTINGS_FRAME_TYPE, 0), &payload);
+            assert!(matches!(result, Err(Http2Error::FrameSize)));
+        }
+        let result = Frame::parse(header(7, GOAWAY_FRAME_TYPE, 0), &[0; 7]);
+        assert!(matches!(result, Err(Http2Error::FrameSize)));
+        let result = Frame::parse(header(3, PUSH_PROMISE_FRAME_TYPE, 0), &[0; 3]);
+        assert!(matches!(result, Err(Http2Error::FrameSize)));
+    }
+
+    #[test]
+    fn payload_must_match_the_header_length() {
+        let result = Frame::parse(header(5, DATA_FRAME_TYPE, 0), b"four");
+        assert!(matches!(result, Err(Http2Error::FrameSize)));
+    }
+
+    #[test]
+    fn padding_must_fit_in_the_payload() {
+        // The padding length byte itself is missing
+        let result = Frame::parse(header(0, DATA_FRAME_TYPE, PADDED_FLAG), &[]);
+        assert!(matches!(result, Err(Http2Error::FrameSize)));
+
+        // Padding may take up everything after its length byte, not more
+        let data = Frame::parse(header(4, DATA_FRAME_TYPE, PADDED_FLAG), &[3, 0, 0, 0]).unwrap();
+        assert_eq!(data, Frame::Data { stream_id: 1, end_stream: false, padding: Some(3), data: Vec::new() });
+        for type_ in [DATA_FRAME_TYPE, HEADERS_FRAME_TYPE] {
+            let result = Frame::parse(header(4, type_, PADDED_FLAG), &[4, 0, 0, 0]);
+            assert!(matches!(result, Err(Http2Error::Protocol(PROTOCOL_ERROR))));
+        }
+
+        // The priority section comes before the padding
+        let payload = [1, 0, 0, 0, 3, 15, 0];
+        let result = Frame::parse(header(7, HEADERS_FRAME_TYPE, PADDED_FLAG | PRIORITY_FLAG), &payload);
+        assert!(matches!(result, Ok(Frame::Headers { priority: Some(_), .. })));
+        let payload = [3, 0, 0, 0, 3, 15, 0];
+        let result = Frame::parse(header(7, HEADERS_FRAME_TYPE, PADDED_FLAG | PRIORITY_FLAG), &payload);
+        assert!(matches!(result, Err(Http2Error::FrameSize)));
+    }
+
+    #[test]
+    fn reads_up_to_the_exact_end() {
+        let bytes = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 0x80, 0, 0, 1];
+        let mut reader = ByteReader::new("TEST", &bytes);
+        assert_eq!(reader.read_u8("u8").unwrap(), 1);
+        assert_eq!(reader.read_u16("u16").unwrap(), 0x0203);
+        assert_eq!(reader.read_u32("u32").unwrap(), 0x0405_0607);
+        assert_eq!(reader.read_array::<3>("array").unwrap(), [8, 9, 10]);
+        assert_eq!(reader.remaining(), 4);
+        assert_eq!(reader.read_u31("u31").unwrap(), 1);
+        assert_eq!(reader.remaining(), 0);
+        assert_eq!(reader.read_bytes(0, "nothing").unwrap(), []);
+        assert_eq!(reader.read_rest(), []);
+    }
+
+    #[test]
+    fn truncated_reads_fail_without_consuming() {
+        for length in 0..4 {
+            let bytes = vec![0xff; length];
+            let mut reader = ByteReader::new("TEST", &bytes);
+            assert!(matches!(reader.read_u32("u32"), Err(Http2Error::FrameSize)));
+            assert!(matches!(reader.read_u31("u31"), Err(Http2Error::FrameSize)));
+            assert!(matches!(reader.read_array::<8>("array"), Err(Http2Error::FrameSize)));
+            assert_eq!(reader.remaining(), length);
+            if length < 2 {
+                assert!(matches!(reader.read_u16("u16"), Err(Http2Error::FrameSize)));
+            }
+        }
+
+        let mut reader = ByteReader::new("TEST", &[]);
+        assert!(matches!(reader.read_u8("u8"), Err(Http2Error::FrameSize)));
+        assert!(matches!(reader.read_bytes(1, "bytes"), Err(Http2Error::FrameSize)));
+        assert!(matches!(reader.read_bytes(usize::MAX, "bytes"), Err(Http2Error::FrameSize)));
+    }
+
+    #[test]
+    fn cutting_the_end_stops_at_the_start() {
+        let mut reader = ByteReader::new("TEST", &[1, 2, 3]);
+        assert!(!reader.truncate_end(4));
+        assert_eq!(reader.remaining(), 3);
+        assert!(reader.truncate_end(1));
+        assert!(reader.truncate_end(2));
+        assert_eq!(reader.remaining(), 0);
+        assert!(reader.truncate_end(0));
+        assert!(!reader.truncate_end(1));
+    }
+
+    #[test]
+    fn length_checks_are_exact() {
+        let reader = ByteReader::new("TEST", &[0; 4]);
+        assert!(reader.expect_length(4).is_ok());
+        assert!(matches!(reader.expect_length(3), Err(Http2Error::FrameSize)));
+        assert!(matches!(reader.expect_length(5), Err(Http2Error::FrameSize)));
+    }
+}
@@ -1,371 +1,241 @@
-use std::net::{TcpListener, TcpStream};
-use std::io::{Read, Write};
-use hpack::Decoder;
-
-// Constants for frame types
-const SETTINGS_FRAME_TYPE: u8 = 0x04;
-const HEADERS_FRAME_TYPE: u8 = 0x01;
-const WINDOW_UPDATE_FRAME_TYPE: u8 = 0x08;
-const GOAWAY_FRAME_TYPE: u8 = 0x07;
-
-// Constants for settings keys
-const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x03;
-const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x04;
-const SETTINGS_ENABLE_PUSH: u16 = 0x02;
-
-// Frame header structure
-struct FrameHeader {
-    length: u32,
-    type_: u8,
-    flags: u8,
-    stream_id: u32,
-}
-
-impl FrameHeader {
-    fn from_bytes(bytes: &[u8; 9]) -> Self {
-        let length = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
-        let type_ = bytes[3];
-        let flags = bytes[4];
-        let stream_id = u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]) & 0x7FFFFFFF;
-
-        FrameHeader {
-            length,
-            type_,
-            flags,
-            stream_id,
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread;
+use std::io::{self, ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+mod config;
+mod connection;
+mod event_loop;
+mod frame;
+mod message;
+mod routes;
+mod shutdown;
+#[cfg(feature = "static-files")]
+mod static_files;
+mod warnings;
+
+use config::{IoModel, ServerConfig};
+use connection::Connection;
+use shutdown::{shutdown_requested, SHUTDOWN_POLL_INTERVAL};
+
+// How long, and for how many bytes, we keep reading after our GOAWAY
+const LINGER_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_LINGER_BYTES: usize = 1024 * 1024;
+
+// Writes that fail for lack of kernel buffers are retried this many times
+// in a row, with a growing delay, before the connection is given up on
+const MAX_WRITE_RETRIES: u32 = 5;
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+// errno for a kernel out of buffer space, which differs between Linux and
+// macOS or the BSDs
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const ENOBUFS: i32 = 105;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const ENOBUFS: i32 = 55;
+
+// Closing a socket with unread data makes the kernel reset the connection,
+// which can throw away our GOAWAY before the client reads it. Stop writing
+// and read away what the client still sends, for a short while
+fn linger(mut stream: &TcpStream) {
+    if stream.shutdown(Shutdown::Write).is_err() || stream.set_read_timeout(Some(LINGER_TIMEOUT)).is_err() {
+        return;
+    }
+
+    let start = Instant::now();
+    let mut buffer = [0; 16384];
+    let mut discarded = 0;
+    while discarded < MAX_LINGER_BYTES && start.elapsed() < LINGER_TIMEOUT {
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => discarded += n,
         }
     }
 }
 
-// Server settings
-struct ServerSettings {
-    max_concurrent_streams: u32,
-    initial_window_size: u32,
-    enable_push: bool,
+// Whether a failed write may succeed if tried again later
+fn out_of_buffers(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(ENOBUFS)
 }
 
-impl ServerSettings {
-    fn new() -> Self {
-        ServerSettings {
-            max_concurrent_streams: 100, // Default value
-            initial_window_size: 65535,  // Default value
-            enable_push: true,           // Default value
-        }
-    }
-
-    fn update(&mut self, key: u16, value: u32) {
-        match key {
-            SETTINGS_MAX_CONCURRENT_STREAMS => {
-                self.max_concurrent_streams = value;
-                println!("Updated max_concurrent_streams to {}", value);
-            }
-            SETTINGS_INITIAL_WINDOW_SIZE => {
-                self.initial_window_size = value;
-                println!("Updated initial_window_size to {}", value);
-            }
-            SETTINGS_ENABLE_PUSH => {
-                self.enable_push = value != 0;
-                println!("Updated enable_push to {}", value != 0);
+// Write all of `output` to a blocking socket. A write that runs out of
+// kernel buffers is retried from the byte where the previous one stopped,
+// so no frame is ever sent twice
+fn write_output(mut stream: impl Write, output: &[u8]) -> io::Result<()> {
+    let mut written = 0;
+    let mut retries = 0;
+    while written < output.len() {
+        match stream.write(&output[written..]) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                written += n;
+                retries = 0;
             }
-            _ => {
-                println!("Ignoring unknown setting: key={}, value={}", key, value);
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) if out_of_buffers(&e) && retries < MAX_WRITE_RETRIES => {
+                thread::sleep(WRITE_RETRY_DELAY * 2u32.pow(retries));
+                retries += 1;
             }
+            Err(e) => return Err(e),
         }
     }
+    Ok(())
 }
 
-fn handle_connection_preface(stream: &mut TcpStream) -> bool {
-    let mut preface_buffer = [0; 24];
-    if let Err(_) = stream.read_exact(&mut preface_buffer) {
-        eprintln!("Failed to read connection preface");
-        return false;
-    }
-
-    let expected_preface = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
-    if &preface_buffer[..] == expected_preface {
-        println!("Valid HTTP/2 connection preface received");
-        true
-    } else {
-        eprintln!("Invalid HTTP/2 connection preface");
-        false
-    }
-}
-
-fn send_http2_settings_frame(stream: &mut TcpStream) {
-    // HTTP/2 SETTINGS frame (empty payload for simplicity)
-    let settings_frame = [
-        0x00, 0x00, 0x00, // Length: 0 (empty payload)
-        0x04,             // Type: SETTINGS (4)
-        0x00,             // Flags: None
-        0x00, 0x00, 0x00, 0x00, // Stream ID: 0 (connection-level)
-    ];
-
-    stream.write_all(&settings_frame).unwrap();
-    stream.flush().unwrap();
-}
-
-fn read_client_settings_frame(stream: &mut TcpStream, settings: &mut ServerSettings) -> bool {
-    let mut header_buffer = [0; 9];
-    if let Err(_) = stream.read_exact(&mut header_buffer) {
-        eprintln!("Failed to read frame header");
-        return false;
-    }
-
-    let header = FrameHeader::from_bytes(&header_buffer);
-
-    // Check if this is a SETTINGS frame
-    if header.type_ != SETTINGS_FRAME_TYPE {
-        eprintln!("Expected SETTINGS frame, got frame type {}", header.type_);
-        return false;
-    }
-
-    println!(
-        "Received SETTINGS frame: length={}, flags={}, stream_id={}",
-        header.length, header.flags, header.stream_id
-    );
-
-    // Read the payload (if any)
-    if header.length > 0 {
-        let mut payload = vec![0; header.length as usize];
-        if let Err(_) = stream.read_exact(&mut payload) {
-            eprintln!("Failed to read frame payload");
-            return false;
+// Serve a client on a blocking socket, in a thread of its own
+fn handle_client(mut stream: TcpStream, config: ServerConfig) {
+    // Wake up regularly to notice a shutdown request and timeouts
+    if let Err(e) = stream.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL)) {
+        eprintln!("Failed to set up connection: {}", e);
+        return;
+    }
+
+    let mut connection = Connection::new(config);
+    let mut buffer = [0; 16384];
+    while !connection.is_closed() {
+        match stream.read(&mut buffer) {
+            Ok(0) => connection.receive_eof(),
+            Ok(n) => connection.receive(&buffer[..n]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+            Err(e) => connection.fail(e),
         }
-
-        // Parse the settings
-        for chunk in payload.chunks(6) {
-            if chunk.len() == 6 {
-                let key = u16::from_be_bytes([chunk[0], chunk[1]]);
-                let value = u32::from_be_bytes([chunk[2], chunk[3], chunk[4], chunk[5]]);
-                println!("Setting: key={}, value={}", key, value);
-                settings.update(key, value);
+        connection.on_tick();
+
+        // Taking the output lets paused responses produce their next slice
+        let mut output = connection.take_output();
+        while !output.is_empty() {
+            if let Err(e) = write_output(&stream, &output) {
+                if connection.lingers() {
+                    eprintln!("Failed to send GOAWAY");
+                } else {
+                    connection.fail(e);
+                }
+                return;
             }
+            output = connection.take_output();
         }
     }
 
-    // Send a SETTINGS acknowledgment
-    let ack_frame = [
-        0x00, 0x00, 0x00, // Length: 0 (empty payload)
-        0x04,             // Type: SETTINGS (4)
-        0x01,             // Flags: ACK (0x01)
-        0x00, 0x00, 0x00, 0x00, // Stream ID: 0 (connection-level)
-    ];
-
-    if let Err(_) = stream.write_all(&ack_frame) {
-        eprintln!("Failed to send SETTINGS acknowledgment");
-        return false;
+    if connection.lingers() {
+        linger(&stream);
     }
-
-    true
 }
 
-fn read_window_update_frame(stream: &mut TcpStream, header: FrameHeader) -> bool {
-    println!(
-        "Received WINDOW_UPDATE frame: length={}, flags={}, stream_id={}",
-        header.length, header.flags, header.stream_id
-    );
-
-    // Read the payload (if any)
-    if header.length > 0 {
-        let mut payload = vec![0; header.length as usize];
-        if let Err(_) = stream.read_exact(&mut payload) {
-            eprintln!("Failed to read frame payload");
-            return false;
+// Accept connections and serve each one in a thread of its own
+fn serve_with_threads(listener: TcpListener, config: ServerConfig, address: &'static str) {
+    // The accept loop blocks; once a shutdown is requested, connect to
+    // ourselves to wake it up
+    thread::spawn(move || {
+        while !shutdown_requested() {
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
         }
+        let _ = TcpStream::connect(address);
+    });
 
-        // Parse the window size increment
-        let increment = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
-        println!("Window size increment: {}", increment);
-    }
-
-    true
-}
-
-fn read_headers_frame(stream: &mut TcpStream, header: FrameHeader) -> bool {
-    println!(
-        "Received HEADERS frame: length={}, flags={}, stream_id={}",
-        header.length, header.flags, header.stream_id
-    );
-
-    // Read the payload (if any)
-    if header.length > 0 {
-        let mut payload = vec![0; header.length as usize];
-        if let Err(_) = stream.read_exact(&mut payload) {
-            eprintln!("Failed to read frame payload");
-            return false;
+    let mut connections = Vec::new();
+    for stream in listener.incoming() {
+        if shutdown_requested() {
+            break;
         }
 
-        // Decode the HPACK-compressed headers
-        let mut decoder = Decoder::new();
-        match decoder.decode(&payload) {
-            Ok(headers) => {
-                println!("Decoded headers:");
-                for (name, value) in headers {
-                    println!("{}: {}", String::from_utf8_lossy(&name), String::from_utf8_lossy(&value));
-                }
+        match stream {
+            Ok(stream) => {
+                let config = config.clone();
+                connections.push(thread::spawn(|| {
+                    handle_client(stream, config);
+                }));
             }
             Err(e) => {
-                eprintln!("Failed to decode headers: {:?}", e);
-                return false;
+                eprintln!("Failed to accept connection: {}", e);
             }
         }
+        connections.retain(|connection| !connection.is_finished());
     }
 
-    true
+    // Refuse new connections, and give the open ones time to drain
+    drop(listener);
+    println!("Shutting down, waiting for {} connection(s)", connections.len());
+    for connection in connections {
+        let _ = connection.join();
+    }
 }
 
-fn read_goaway_frame(stream: &mut TcpStream, header: FrameHeader) -> bool {
-    println!(
-        "Received GOAWAY frame: length={}, flags={}, stream_id={}",
-        header.length, header.flags, header.stream_id
-    );
-
-    // Read the payload (if any)
-    if header.length > 0 {
-        let mut payload = vec![0; header.length as usize];
-        if let Err(_) = stream.read_exact(&mut payload) {
-            eprintln!("Failed to read frame payload");
-            return false;
+fn main() {
+    let config = ServerConfig::from_args();
+    let address = "127.0.0.1:8080";
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to bind {}: {}", address, e);
+            return;
         }
+    };
 
-        // Parse the last stream ID and error code
-        let last_stream_id = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
-        let error_code = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
-        println!("Last stream ID: {}", last_stream_id);
-        println!("Error code: {}", error_code);
+    shutdown::handle_sigint();
 
-        // Optionally, parse additional debug data (if present)
-        if header.length > 8 {
-            let debug_data = &payload[8..];
-            println!("Debug data: {:?}", String::from_utf8_lossy(debug_data));
-        }
+    match config.io_model {
+        IoModel::Threads => serve_with_threads(listener, config, address),
+        IoModel::EventLoop => event_loop::run(listener, &config),
     }
-
-    // Close the connection after receiving a GOAWAY frame
-    println!("Closing connection due to GOAWAY frame");
-    false
-}
-
-fn send_response(stream: &mut TcpStream, settings: &ServerSettings) {
-    // Send a HEADERS frame with the response headers
-    let headers_frame = [
-        0x00, 0x00, 0x1D, // Length: 29 bytes (for the headers below)
-        0x01,             // Type: HEADERS (1)
-        0x04,             // Flags: END_HEADERS (0x04)
-        0x00, 0x00, 0x00, 0x01, // Stream ID: 1 (client's request stream)
-        // Headers (simplified for demonstration)
-        b':', b's', b't', b'a', b't', b'u', b's', b' ', b'2', b'0', b'0', b' ', b'\r', b'\n',
-        b'c', b'o', b'n', b't', b'e', b'n', b't', b'-', b'l', b'e', b'n', b'g', b't', b'h', b' ',
-        b'1', b'2', b'\r', b'\n', b'\r', b'\n',
-    ];
-
-    stream.write_all(&headers_frame).unwrap();
-
-    // Send a DATA frame with the response body
-    let data_frame_header = [
-        0x00, 0x00, 0x0C, // Length: 12 bytes (for the body below)
-        0x00,             // Type: DATA (0)
-        0x01,             // Flags: END_STREAM (0x01)
-        0x00, 0x00, 0x00, 0x01, // Stream ID: 1 (client's request stream)
-    ];
-
-    stream.write_all(&data_frame_header).unwrap();
-    stream.write_all(b"Hello, world!").unwrap();
-    stream.flush().unwrap();
 }
 
-fn handle_client(mut stream: TcpStream) {
-    // Step 1: Read and validate the HTTP/2 connection preface
-    if !handle_connection_preface(&mut stream) {
-        return; // Close the connection if the preface is invalid
-    }
-
-    // Step 2: Send the server's SETTINGS frame
-    send_http2_settings_frame(&mut stream);
-
-    // Step 3: Read the client's SETTINGS frame
-    let mut settings = ServerSettings::new();
-    if !read_client_settings_frame(&mut stream, &mut settings) {
-        return; // Close the connection if the frame is invalid
-    }
-
-    // Step 4: Handle frames in a loop
-    loop {
-        let mut header_buffer = [0; 9];
-        if let Err(_) = stream.read_exact(&mut header_buffer) {
-            eprintln!("Failed to read frame header");
-            return; // Close the connection on read error
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A socket that takes at most `limit` bytes per write, and runs out of
+    // kernel buffers `failures` times once `fail_at` bytes are written
+    pub struct ShortWriter {
+        pub written: Vec<u8>,
+        limit: usize,
+        fail_at: usize,
+        failures: u32,
+    }
+
+    impl ShortWriter {
+        pub fn new(limit: usize, fail_at: usize, failures: u32) -> Self {
+            ShortWriter {
+                written: Vec::new(),
+                limit,
+                fail_at,
+                failures,
+            }
         }
+    }
 
-        let header = FrameHeader::from_bytes(&header_buffer);
-
-        match header.type_ {
-            WINDOW_UPDATE_FRAME_TYPE => {
-                if !read_window_update_frame(&mut stream, header) {
-                    return; // Close the connection if the frame is invalid
-                }
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.written.len() == self.fail_at && self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::from_raw_os_error(ENOBUFS));
             }
-            HEADERS_FRAME_TYPE => {
-                if !read_headers_frame(&mut stream, header) {
-                    return; // Close the connection if the frame is invalid
-                }
-
-                // Send a response
-                send_response(&mut stream, &settings);
+            // Stop short of `fail_at`, so that a write starts right there
+            let mut n = buf.len().min(self.limit);
+            if self.written.len() < self.fail_at {
+                n = n.min(self.fail_at - self.written.len());
             }
-            SETTINGS_FRAME_TYPE => {
-                // Handle additional SETTINGS frames
-                if header.flags & 0x01 == 0x01 {
-                    // This is a SETTINGS acknowledgment (ignore it)
-                    println!("Received SETTINGS acknowledgment");
-                } else {
-                    // This is a new SETTINGS frame
-                    println!("Received additional SETTINGS frame");
-                    if !read_client_settings_frame(&mut stream, &mut settings) {
-                        return; // Close the connection if the frame is invalid
-                    }
-
-                    // Send a SETTINGS acknowledgment
-                    let ack_frame = [
-                        0x00, 0x00, 0x00, // Length: 0 (empty payload)
-                        0x04,             // Type: SETTINGS (4)
-                        0x01,             // Flags: ACK (0x01)
-                        0x00, 0x00, 0x00, 0x00, // Stream ID: 0 (connection-level)
-                    ];
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
 
-                    if let Err(_) = stream.write_all(&ack_frame) {
-                        eprintln!("Failed to send SETTINGS acknowledgment");
-                        return;
-                    }
-                }
-            }
-            GOAWAY_FRAME_TYPE => {
-                // Handle GOAWAY frame
-                if !read_goaway_frame(&mut stream, header) {
-                    return; // Close the connection
-                }
-            }
-            _ => {
-                eprintln!("Unexpected frame type: {}", header.type_);
-                return; // Close the connection on unexpected frame types
-            }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
         }
     }
-}
 
-fn main() {
-    let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
-
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                std::thread::spawn(|| {
-                    handle_client(stream);
-                });
-            }
-            Err(e) => {
-                eprintln!("Failed to accept connection: {}", e);
-            }
+    #[test]
+    fn writes_resume_where_buffers_ran_out() {
+        let output: Vec<u8> = (0..40).collect();
+        for fail_at in 0..output.len() {
+            let mut writer = ShortWriter::new(7, fail_at, 1);
+            write_output(&mut writer, &output).unwrap();
+            assert_eq!(writer.written, output, "failing at {}", fail_at);
         }
+
+        // Retries that never get anywhere end the connection
+        let mut writer = ShortWriter::new(7, 10, MAX_WRITE_RETRIES + 1);
+        let e = write_output(&mut writer, &output).unwrap_err();
+        assert!(out_of_buffers(&e));
+        assert_eq!(writer.written, output[..10]);
     }
 }
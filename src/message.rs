@@ -0,0 +1,408 @@
+// Requests and responses as the handlers see them, whichever protocol
+// carried them
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+#[cfg(feature = "static-files")]
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::OnceLock;
+#[cfg(feature = "static-files")]
+use std::io::Read;
+
+use crate::config::SpecLevel;
+
+// HPACK static table (RFC 7541, Appendix A); entry i has index i + 1
+pub const STATIC_TABLE: [(&[u8], &[u8]); 61] = [
+    (b":authority", b""), (b":method", b"GET"), (b":method", b"POST"), (b":path", b"/"),
+    (b":path", b"/index.html"), (b":scheme", b"http"), (b":scheme", b"https"), (b":status", b"200"),
+    (b":status", b"204"), (b":status", b"206"), (b":status", b"304"), (b":status", b"400"),
+    (b":status", b"404"), (b":status", b"500"), (b"accept-charset", b""), (b"accept-encoding", b"gzip, deflate"),
+    (b"accept-language", b""), (b"accept-ranges", b""), (b"accept", b""), (b"access-control-allow-origin", b""),
+    (b"age", b""), (b"allow", b""), (b"authorization", b""), (b"cache-control", b""),
+    (b"content-disposition", b""), (b"content-encoding", b""), (b"content-language", b""), (b"content-length", b""),
+    (b"content-location", b""), (b"content-range", b""), (b"content-type", b""), (b"cookie", b""),
+    (b"date", b""), (b"etag", b""), (b"expect", b""), (b"expires", b""),
+    (b"from", b""), (b"host", b""), (b"if-match", b""), (b"if-modified-since", b""),
+    (b"if-none-match", b""), (b"if-range", b""), (b"if-unmodified-since", b""), (b"last-modified", b""),
+    (b"link", b""), (b"location", b""), (b"max-forwards", b""), (b"proxy-authenticate", b""),
+    (b"proxy-authorization", b""), (b"range", b""), (b"referer", b""), (b"refresh", b""),
+    (b"retry-after", b""), (b"server", b""), (b"set-cookie", b""), (b"strict-transport-security", b""),
+    (b"transfer-encoding", b""), (b"user-agent", b""), (b"vary", b""), (b"via", b""),
+    (b"www-authenticate", b""),
+];
+
+// Request header names worth sharing that the static table lacks
+const COMMON_HEADER_NAMES: [&str; 16] = [
+    "connection", "dnt", "http2-settings", "origin", "priority", "sec-ch-ua", "sec-ch-ua-mobile",
+    "sec-ch-ua-platform", "sec-fetch-dest", "sec-fetch-mode", "sec-fetch-site", "sec-fetch-user", "te", "upgrade",
+    "upgrade-insecure-requests", "x-requested-with",
+];
+
+// Decoded header fields, as (name, value) pairs in wire order
+pub type HeaderList = Vec<(Vec<u8>, Vec<u8>)>;
+
+// Request header fields by lowercase name. Well-known names are shared
+// rather than allocated for every request
+pub type HeaderMap = BTreeMap<Cow<'static, str>, String>;
+
+// Body of a response: bytes in memory, or a file read only as its
+// slices are sent
+pub enum Body {
+    Bytes(Vec<u8>),
+    #[cfg(feature = "static-files")]
+    File { file: File, length: u64 },
+}
+
+impl Body {
+    pub fn len(&self) -> u64 {
+        match self {
+            Body::Bytes(bytes) => bytes.len() as u64,
+            #[cfg(feature = "static-files")]
+            Body::File { length, .. } => *length,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The `length` bytes from `start` on. Slices of a file must be taken
+    // in order, one after the other
+    pub fn slice(&mut self, start: u64, length: usize) -> io::Result<Cow<'_, [u8]>> {
+        match self {
+            Body::Bytes(bytes) => Ok(Cow::Borrowed(&bytes[start as usize..start as usize + length])),
+            #[cfg(feature = "static-files")]
+            Body::File { file, .. } => {
+                let mut slice = vec![0; length];
+                file.read_exact(&mut slice)?;
+                Ok(Cow::Owned(slice))
+            }
+        }
+    }
+
+    // Write the whole body at once
+    pub fn write_to(&mut self, writer: &mut impl Write) -> io::Result<()> {
+        match self {
+            Body::Bytes(bytes) => writer.write_all(bytes),
+            #[cfg(feature = "static-files")]
+            Body::File { file, length } => {
+                let copied = io::copy(&mut file.take(*length), writer)?;
+                if copied < *length {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Response to a request: status, regular header fields and body
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn new(status: u16, body: Vec<u8>) -> Self {
+        Response::with_body(status, Body::Bytes(body))
+    }
+
+    // Response with the contents of a file of `length` bytes, which are
+    // read as they are sent
+    #[cfg(feature = "static-files")]
+    pub fn file(status: u16, file: File, length: u64) -> Self {
+        Response::with_body(status, Body::File { file, length })
+    }
+
+    fn with_body(status: u16, body: Body) -> Self {
+        let mut response = Response {
+            status,
+            headers: Vec::new(),
+            body,
+        };
+        response.set("content-length", &response.body.len().to_string());
+        response
+    }
+
+    // Plain text response
+    pub fn text(status: u16, body: &str) -> Self {
+        let mut response = Response::new(status, body.as_bytes().to_vec());
+        response.set("content-type", "text/plain");
+        response
+    }
+
+    // Replace all values of a header with a single one, keeping the
+    // position of its first occurrence
+    pub fn set(&mut self, name: &str, value: &str) {
+        let name = name.to_ascii_lowercase();
+        match self.headers.iter().position(|(n, _)| *n == name) {
+            Some(index) => {
+                self.headers[index].1 = value.to_string();
+                let mut i = 0;
+                self.headers.retain(|(n, _)| {
+                    i += 1;
+                    i - 1 == index || *n != name
+                });
+            }
+            None => self.headers.push((name, value.to_string())),
+        }
+    }
+
+    // Add a value for a header, keeping existing ones (set-cookie, vary).
+    // Repeated fields are grouped together, which helps HPACK. No route
+    // repeats a header yet, so only tests build it
+    #[cfg(test)]
+    pub fn append(&mut self, name: &str, value: &str) {
+        let name = name.to_ascii_lowercase();
+        match self.headers.iter().rposition(|(n, _)| *n == name) {
+            Some(index) => self.headers.insert(index + 1, (name, value.to_string())),
+            None => self.headers.push((name, value.to_string())),
+        }
+    }
+}
+
+// Request line pseudo-headers and regular header fields of a request
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub scheme: Option<String>,
+    pub authority: Option<String>,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    // Build a request from a decoded header block, checking that the
+    // pseudo-headers come first and that :method and :path are present
+    pub fn from_headers(fields: &HeaderList, spec_level: SpecLevel) -> Result<Self, &'static str> {
+        let mut method = None;
+        let mut path = None;
+        let mut scheme = None;
+        let mut authority = None;
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in fields {
+            if name.iter().any(|b| b.is_ascii_uppercase()) {
+                return Err("uppercase header name");
+            }
+            if spec_level.strict_field_validation() {
+                validate_field(name, value)?;
+            }
+
+            let name = intern_header_name(name);
+            let value = String::from_utf8_lossy(value).into_owned();
+
+            if name.starts_with(':') {
+                if !headers.is_empty() {
+                    return Err("pseudo-header after a regular header");
+                }
+                let slot = match &*name {
+                    ":method" => &mut method,
+                    ":path" => &mut path,
+                    ":scheme" => &mut scheme,
+                    ":authority" => &mut authority,
+                    _ => return Err("unknown pseudo-header"),
+                };
+                if slot.replace(value).is_some() {
+                    return Err("duplicate pseudo-header");
+                }
+                continue;
+            }
+
+            // Repeated fields are combined, cookies with their own separator
+            let separator = if name == "cookie" { "; " } else { ", " };
+            headers
+                .entry(name)
+                .and_modify(|existing| {
+                    existing.push_str(separator);
+                    existing.push_str(&value);
+                })
+                .or_insert(value);
+        }
+
+        Ok(Request {
+            method: method.ok_or("missing :method")?,
+            path: path.ok_or("missing :path")?,
+            scheme,
+            authority,
+            headers,
+            body: Vec::new(),
+        })
+    }
+}
+
+// Names of the static table and the common names, sorted and without
+// repeats, built on first use
+fn known_header_names() -> &'static [&'static str] {
+    static NAMES: OnceLock<Vec<&'static str>> = OnceLock::new();
+    NAMES.get_or_init(|| {
+        let table_names = STATIC_TABLE.iter().filter_map(|(name, _)| std::str::from_utf8(name).ok());
+        let mut names: Vec<&'static str> = table_names.chain(COMMON_HEADER_NAMES).collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    })
+}
+
+// A header name in lowercase, borrowed from the static table or the common
+// names when it is one of them
+pub fn intern_header_name(name: &[u8]) -> Cow<'static, str> {
+    let names = known_header_names();
+    let lowercase = || name.iter().map(u8::to_ascii_lowercase);
+    match names.binary_search_by(|known| known.bytes().cmp(lowercase())) {
+        Ok(index) => Cow::Borrowed(names[index]),
+        Err(_) => Cow::Owned(String::from_utf8_lossy(name).to_ascii_lowercase()),
+    }
+}
+
+// RFC 9113 section 8.2.1 field validity checks
+fn validate_field(name: &[u8], value: &[u8]) -> Result<(), &'static str> {
+    // A leading colon is only allowed for pseudo-headers
+    let regular_name = name.strip_prefix(b":").unwrap_or(name);
+    if regular_name.is_empty()
+        || regular_name.iter().any(|&b| b <= 0x20 || b >= 0x7f || b == b':')
+    {
+        return Err("invalid character in header name");
+    }
+
+    if value.iter().any(|&b| b == 0x00 || b == b'\r' || b == b'\n') {
+        return Err("invalid character in header value");
+    }
+
+    let is_whitespace = |b: &u8| *b == b' ' || *b == b'\t';
+    if value.first().is_some_and(is_whitespace) || value.last().is_some_and(is_whitespace) {
+        return Err("whitespace around header value");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    fn header_names(response: &Response) -> Vec<(&str, &str)> {
+        response.headers.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect()
+    }
+
+    #[test]
+    fn appended_values_are_grouped_in_order() {
+        let mut response = Response::new(200, b"ok".to_vec());
+        response.append("Set-Cookie", "a=1");
+        response.append("vary", "accept");
+        response.append("set-cookie", "b=2");
+        response.append("Vary", "origin");
+        assert_eq!(
+            header_names(&response),
+            [
+                ("content-length", "2"),
+                ("set-cookie", "a=1"),
+                ("set-cookie", "b=2"),
+                ("vary", "accept"),
+                ("vary", "origin"),
+            ]
+        );
+
+        // Setting a repeated header leaves one value, where the first was
+        response.set("set-cookie", "c=3");
+        assert_eq!(header_names(&response)[1..], [("set-cookie", "c=3"), ("vary", "accept"), ("vary", "origin")]);
+    }
+
+    fn parse_request(fields: &[(&str, &str)]) -> Result<Request, &'static str> {
+        parse_request_as(SpecLevel::Rfc9113, fields)
+    }
+
+    fn parse_request_as(spec_level: SpecLevel, fields: &[(&str, &str)]) -> Result<Request, &'static str> {
+        let fields = fields.iter().map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()));
+        Request::from_headers(&fields.collect(), spec_level)
+    }
+
+    #[test]
+    fn requests_start_with_their_pseudo_headers() {
+        let fields = [
+            (":method", "GET"),
+            (":path", "/"),
+            ("cookie", "a=1"),
+            ("accept", "x"),
+            ("cookie", "b=2"),
+            ("accept", "y"),
+        ];
+        let request = parse_request(&fields).unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("GET", "/"));
+        assert_eq!(request.headers["cookie"], "a=1; b=2");
+        assert_eq!(request.headers["accept"], "x, y");
+
+        let malformed = [
+            (&[(":method", "GET")][..], "missing :path"),
+            (&[(":method", "GET"), ("accept", "x"), (":path", "/")], "pseudo-header after a regular header"),
+            (&[(":method", "GET"), (":path", "/"), (":path", "/")], "duplicate pseudo-header"),
+            (&[(":method", "GET"), (":path", "/"), (":protocol", "websocket")], "unknown pseudo-header"),
+        ];
+        for (fields, reason) in malformed {
+            assert_eq!(parse_request(fields).err(), Some(reason));
+        }
+    }
+
+    #[test]
+    fn only_rfc9113_validates_field_characters() {
+        let invalid = [
+            (("x-name", " padded"), "whitespace around header value"),
+            (("x-name", "carriage\rreturn"), "invalid character in header value"),
+            (("x name", "value"), "invalid character in header name"),
+            (("x:name", "value"), "invalid character in header name"),
+        ];
+        for (field, reason) in invalid {
+            let fields = [(":method", "GET"), (":path", "/"), field];
+            assert_eq!(parse_request_as(SpecLevel::Rfc9113, &fields).err(), Some(reason));
+            assert!(parse_request_as(SpecLevel::Rfc7540, &fields).is_ok());
+        }
+
+        // Uppercase names are malformed under both levels
+        let fields = [(":method", "GET"), (":path", "/"), ("X-Name", "value")];
+        for spec_level in [SpecLevel::Rfc7540, SpecLevel::Rfc9113] {
+            assert_eq!(parse_request_as(spec_level, &fields).err(), Some("uppercase header name"));
+        }
+    }
+
+    #[test]
+    fn known_names_are_shared_whatever_their_case() {
+        assert!(known_header_names().windows(2).all(|pair| pair[0] < pair[1]));
+        for (name, _) in STATIC_TABLE {
+            let name = std::str::from_utf8(name).unwrap();
+            assert!(matches!(intern_header_name(name.as_bytes()), Cow::Borrowed(known) if known == name));
+        }
+        for name in COMMON_HEADER_NAMES {
+            assert!(matches!(intern_header_name(name.as_bytes()), Cow::Borrowed(known) if known == name));
+        }
+        assert!(matches!(intern_header_name(b"Content-Type"), Cow::Borrowed("content-type")));
+        assert!(matches!(intern_header_name(b"X-Custom"), Cow::Owned(name) if name == "x-custom"));
+        assert!(matches!(intern_header_name(b"content-typ"), Cow::Owned(_)));
+        assert!(matches!(intern_header_name(b""), Cow::Owned(_)));
+    }
+
+    // Rough timing, printed with --nocapture: a lookup is a binary search
+    // over the 70-odd names
+    #[test]
+    fn lookups_are_quick() {
+        let unknown: [&[u8]; 3] = [b"X-Forwarded-For", b"x-request-id", b"sec-ch-ua-arch"];
+        for name in COMMON_HEADER_NAMES {
+            assert!(matches!(intern_header_name(name.as_bytes()), Cow::Borrowed(known) if known == name));
+        }
+        for name in unknown {
+            assert!(matches!(intern_header_name(name), Cow::Owned(_)), "{:?}", name);
+        }
+
+        let names: Vec<&[u8]> = COMMON_HEADER_NAMES.iter().map(|name| name.as_bytes()).chain(unknown).collect();
+        let start = Instant::now();
+        for _ in 0..10_000 {
+            for name in &names {
+                std::hint::black_box(intern_header_name(name));
+            }
+        }
+        println!("{:?} per lookup", start.elapsed() / (10_000 * names.len() as u32));
+    }
+}
@@ -0,0 +1,62 @@
+// Request handlers, by path
+
+use std::time::Instant;
+
+use crate::config::ServerConfig;
+use crate::message::{Request, Response};
+#[cfg(feature = "static-files")]
+use crate::static_files;
+
+// Produce the response for a request, timing the handler if asked to
+pub fn handle_request(config: &ServerConfig, request: &Request) -> Response {
+    println!("{} {}", request.method, request.path);
+
+    let start = Instant::now();
+    let mut response = route_request(config, request);
+    // Only ever in the response headers: neither HTTP/2 nor HTTP/1.1
+    // responses have a way to send trailers here, and the headers go out
+    // before a streamed file body is read
+    if config.server_timing {
+        let duration = start.elapsed().as_secs_f64() * 1000.0;
+        response.set("server-timing", &format!("handler;dur={:.3}", duration));
+    }
+    response
+}
+
+// Dispatch a request on its path
+fn route_request(config: &ServerConfig, request: &Request) -> Response {
+    // Routes match on the path alone, without the query string
+    let route = request.path.split('?').next().unwrap_or_default();
+
+    match route {
+        "/echo" if !request.body.is_empty() => {
+            // Echo the request body back
+            let mut response = Response::new(200, request.body.clone());
+            if let Some(content_type) = request.headers.get("content-type") {
+                response.set("content-type", content_type);
+            }
+            response
+        }
+        "/echo" => {
+            // Without a body, echo the request headers back as the body
+            let mut body = format!(":method: {}\n:path: {}\n", request.method, request.path);
+            if let Some(scheme) = &request.scheme {
+                body.push_str(&format!(":scheme: {}\n", scheme));
+            }
+            if let Some(authority) = &request.authority {
+                body.push_str(&format!(":authority: {}\n", authority));
+            }
+            for (name, value) in &request.headers {
+                body.push_str(&format!("{}: {}\n", name, value));
+            }
+            Response::text(200, &body)
+        }
+        _ => match &config.root {
+            #[cfg(feature = "static-files")]
+            Some(root) => static_files::respond(root, request, route),
+            // Without a root directory there is a single fixed page
+            _ if route == "/" => Response::text(200, "Hello, world!"),
+            _ => Response::text(404, "Not Found"),
+        },
+    }
+}
@@ -0,0 +1,36 @@
+// Graceful shutdown on SIGINT
+
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// How often blocked threads and the event loop check whether a shutdown
+// was requested, and whether a timeout expired
+pub const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// Set once SIGINT arrives: no new connections or streams are accepted,
+// and open streams get the drain timeout to finish
+pub static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+extern "C" {
+    fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    fn _exit(status: c_int) -> !;
+}
+
+const SIGINT: c_int = 2;
+
+extern "C" fn on_sigint(_signum: c_int) {
+    // A second Ctrl-C stops waiting for connections to drain
+    if SHUTDOWN.swap(true, Ordering::SeqCst) {
+        unsafe { _exit(130) };
+    }
+}
+
+// Request a shutdown on the first SIGINT
+pub fn handle_sigint() {
+    unsafe { signal(SIGINT, on_sigint) };
+}
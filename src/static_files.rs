@@ -0,0 +1,183 @@
+// Static files served from the --root directory
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::message::{Request, Response};
+
+// Answer a request for a file; only GET is allowed
+pub fn respond(root: &Path, request: &Request, route: &str) -> Response {
+    if request.method != "GET" {
+        let mut response = Response::text(405, "Method Not Allowed");
+        response.set("allow", "GET");
+        return response;
+    }
+
+    serve_file(root, route)
+}
+
+// Respond with the file a path names below the root directory. A
+// directory is served through its index.html
+fn serve_file(root: &Path, route: &str) -> Response {
+    let mut path = match resolve_path(root, route) {
+        Some(path) => path,
+        None => return Response::text(404, "Not Found"),
+    };
+    if path.is_dir() {
+        path.push("index.html");
+    }
+
+    // Symbolic links must not lead out of the root either. The file that
+    // was checked is the one opened: the path it was found by may lead
+    // elsewhere by the time it is opened
+    let real_path = match fs::canonicalize(&path) {
+        Ok(real_path) if real_path.starts_with(root) => real_path,
+        _ => return Response::text(404, "Not Found"),
+    };
+
+    match open_file(&real_path) {
+        Ok(Some((file, length))) => {
+            let mut response = Response::file(200, file, length);
+            response.set("content-type", content_type(&path));
+            response
+        }
+        Ok(None) => Response::text(404, "Not Found"),
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", real_path.display(), e);
+            Response::text(404, "Not Found")
+        }
+    }
+}
+
+// A regular file and its length, or None for anything else. The file is
+// read only as the response goes out, a slice at a time
+fn open_file(path: &Path) -> io::Result<Option<(File, u64)>> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+    Ok(Some((file, metadata.len())))
+}
+
+// Map a request path to a file below the root, refusing anything that
+// could step outside of it, whether written plainly or percent-encoded
+fn resolve_path(root: &Path, route: &str) -> Option<PathBuf> {
+    let decoded = String::from_utf8(percent_decode(route)?).ok()?;
+    if decoded.contains(['\\', '\0']) {
+        return None;
+    }
+
+    let mut path = root.to_path_buf();
+    for component in Path::new(decoded.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(segment) => path.push(segment),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+// Decode %XX escapes, XX being two hex digits; a malformed escape makes
+// the whole path invalid
+fn percent_decode(input: &str) -> Option<Vec<u8>> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(decoded)
+}
+
+// Content type for a file, by its extension
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn escapes_need_two_hex_digits() {
+        assert_eq!(percent_decode("/a%20b%2F%2e").as_deref(), Some(&b"/a b/."[..]));
+        for path in ["/%+f", "/%-1", "/%2", "/%", "/%zz", "/% 1"] {
+            assert_eq!(percent_decode(path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn request_paths_stay_below_the_root() {
+        let root = Path::new("/srv");
+        assert_eq!(resolve_path(root, "/"), Some(root.to_path_buf()));
+        assert_eq!(resolve_path(root, "/css/./site.css"), Some(root.join("css/site.css")));
+        assert_eq!(resolve_path(root, "/s%69te.css"), Some(root.join("site.css")));
+        assert_eq!(resolve_path(root, "/a/./b%2ehtml"), Some(root.join("a/b.html")));
+        let routes = [
+            "/../etc/passwd",
+            "/%2e%2e/etc/passwd",
+            "/css/%2E%2E/%2E%2E/x",
+            "/a/%2e%2e/%2e%2e/etc",
+            "/a%5cb",
+            "/a%00b",
+            "/%zz",
+            "/%+f",
+            "/a%4",
+        ];
+        for route in routes {
+            assert_eq!(resolve_path(root, route), None, "{}", route);
+        }
+    }
+
+    #[test]
+    fn links_out_of_the_root_are_not_followed() {
+        let dir = env::temp_dir().join(format!("static-files-{}", process::id()));
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        fs::write(root.join("docs/index.html"), "<p>docs</p>").unwrap();
+        symlink(dir.join("secret.txt"), root.join("leak.txt")).unwrap();
+        symlink(root.join("docs/index.html"), root.join("inside.html")).unwrap();
+        let root = fs::canonicalize(&root).unwrap();
+
+        let mut docs = serve_file(&root, "/docs/");
+        let mut body = Vec::new();
+        docs.body.write_to(&mut body).unwrap();
+        assert_eq!((docs.status, &body[..]), (200, &b"<p>docs</p>"[..]));
+        assert_eq!(serve_file(&root, "/inside.html").status, 200);
+        assert_eq!(serve_file(&root, "/leak.txt").status, 404);
+        assert_eq!(serve_file(&root, "/missing.txt").status, 404);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+// Warnings about what a client does, limited per connection so that a
+// client provoking the same one over and over cannot flood the log. The
+// connection being processed keeps its counts in a thread-local while it
+// runs, which covers every warning emitted on its behalf
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+// How many times each warning is logged on one connection; later ones are
+// only counted
+const LOGGED_REPEATS: u64 = 10;
+
+// Warnings emitted on one connection, by format string
+#[derive(Default)]
+pub struct WarningLog {
+    counts: BTreeMap<&'static str, u64>,
+}
+
+impl WarningLog {
+    // How often a warning was emitted, logged or not
+    #[cfg(test)]
+    pub fn count(&self, format: &str) -> u64 {
+        self.counts.get(format).copied().unwrap_or(0)
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<WarningLog>> = const { RefCell::new(None) };
+}
+
+// Log a warning, unless the current connection already logged this one
+// often enough
+macro_rules! warning {
+    ($format:literal $(, $arg:expr)* $(,)?) => {
+        if $crate::warnings::should_log($format) {
+            eprintln!($format $(, $arg)*);
+        }
+    };
+}
+pub(crate) use warning;
+
+// Count warnings in `log` until `leave`, which hands it back
+pub fn enter(log: WarningLog) {
+    CURRENT.with(|current| *current.borrow_mut() = Some(log));
+}
+
+pub fn leave() -> WarningLog {
+    CURRENT.with(|current| current.borrow_mut().take()).unwrap_or_default()
+}
+
+// Count a warning, and tell whether it should be logged. Outside of a
+// connection every warning is
+pub fn should_log(format: &'static str) -> bool {
+    CURRENT.with(|current| match current.borrow_mut().as_mut() {
+        Some(log) => {
+            let count = log.counts.entry(format).or_insert(0);
+            *count += 1;
+            if *count == LOGGED_REPEATS + 1 {
+                eprintln!("Only counting further warnings on this connection like: {}", format);
+            }
+            *count <= LOGGED_REPEATS
+        }
+        None => true,
+    })
+}
+
+// Report the warnings of the current connection that were counted but not
+// logged
+pub fn log_suppressed() {
+    CURRENT.with(|current| {
+        if let Some(log) = current.borrow().as_ref() {
+            for (format, count) in &log.counts {
+                if *count > LOGGED_REPEATS {
+                    eprintln!("Suppressed {} warning(s) like: {}", count - LOGGED_REPEATS, format);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_are_counted_but_not_logged() {
+        enter(WarningLog::default());
+        let logged = (0..10_000).filter(|_| should_log("same warning")).count();
+        assert!(should_log("other warning"));
+        let log = leave();
+
+        assert_eq!(logged as u64, LOGGED_REPEATS);
+        assert_eq!(log.count("same warning"), 10_000);
+        assert_eq!(log.count("other warning"), 1);
+    }
+
+    #[test]
+    fn warnings_outside_a_connection_are_always_logged() {
+        assert!((0..100).all(|_| should_log("no connection")));
+        assert_eq!(leave().count("no connection"), 0);
+    }
+}